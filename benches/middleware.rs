@@ -0,0 +1,68 @@
+//! the overhead per request of the middleware, through `Service::call` and the response future,
+//! with a trivial inner service which is ready right away.
+
+use std::convert::Infallible;
+
+use axum::body::Body;
+use axum::http::{Request, Response};
+use axum_otel_metrics::{HttpMetricsLayer, HttpMetricsLayerBuilder, RequestBody};
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures_util::FutureExt;
+use opentelemetry_sdk::metrics::{ManualReader, SdkMeterProvider};
+use tower::{service_fn, Layer, Service};
+
+fn layer(builder: HttpMetricsLayerBuilder) -> HttpMetricsLayer {
+    let provider = SdkMeterProvider::builder().with_reader(ManualReader::default()).build();
+    builder.with_provider(provider).build()
+}
+
+fn bench_service<S>(c: &mut Criterion, name: &str, mut svc: S)
+where
+    S: Service<Request<Body>, Error = Infallible>,
+{
+    c.bench_function(name, |b| {
+        b.iter(|| {
+            let req = Request::get("/users/1").body(Body::empty()).unwrap();
+            let res = svc.call(req).now_or_never().unwrap().unwrap();
+            // the response body records its size when dropped
+            drop(res);
+        })
+    });
+}
+
+fn middleware(c: &mut Criterion) {
+    // the inner service alone, the overhead of the middleware is the difference to it
+    bench_service(
+        c,
+        "baseline_no_layer",
+        service_fn(|_req: Request<Body>| async { Ok::<_, Infallible>(Response::new(Body::empty())) }),
+    );
+
+    let inner = service_fn(|_req: Request<RequestBody<Body>>| async { Ok::<_, Infallible>(Response::new(Body::empty())) });
+    bench_service(c, "default", layer(HttpMetricsLayerBuilder::new()).layer(inner));
+    bench_service(
+        c,
+        "exact_body_sizes",
+        layer(
+            HttpMetricsLayerBuilder::new()
+                .with_exact_request_size(true)
+                .with_exact_response_size(true),
+        )
+        .layer(inner),
+    );
+    bench_service(
+        c,
+        "all_metrics",
+        layer(
+            HttpMetricsLayerBuilder::new()
+                .with_request_counter(true)
+                .with_header_size_metric(true)
+                .with_ttfb(true)
+                .with_status_class_attribute(true),
+        )
+        .layer(inner),
+    );
+}
+
+criterion_group!(benches, middleware);
+criterion_main!(benches);
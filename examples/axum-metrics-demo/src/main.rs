@@ -13,7 +13,8 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 use opentelemetry::metrics::Counter;
 use opentelemetry::{global, KeyValue};
-use prometheus::{Encoder, TextEncoder};
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::Resource;
 
 mod sub;
 
@@ -32,17 +33,26 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    let registry = prometheus::Registry::new();
+    let exporter = opentelemetry_prometheus::exporter()
+        .with_registry(registry.clone())
+        .build()
+        .unwrap();
+    let provider = SdkMeterProvider::builder()
+        .with_resource(Resource::new(vec![
+            KeyValue::new("service.name", env!("CARGO_PKG_NAME")),
+            KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+        ]))
+        .with_reader(exporter)
+        .build();
+    // the `foobar` counter below is recorded through the global meter provider
+    global::set_meter_provider(provider.clone());
+
     let metrics = HttpMetricsLayerBuilder::new()
-        .with_service_name(env!("CARGO_PKG_NAME").to_string())
-        .with_service_version(env!("CARGO_PKG_VERSION").to_string())
-        .with_target_labels(vec![KeyValue::new("env", "dev")])
+        .with_provider(provider)
+        .with_prometheus_registry(registry)
+        .with_constant_labels(vec![KeyValue::new("env", "dev")])
         .with_skipper(PathSkipper::new(|s| s.starts_with("/skip")))
-        .with_metric_reader(
-            opentelemetry_prometheus::exporter()
-                .with_registry(prometheus::default_registry().clone())
-                .build()
-                .unwrap()
-        )
         .build();
 
     let state = SharedState {
@@ -52,16 +62,8 @@ async fn main() {
 
     // build our application with a route
     let app = Router::new()
-        .route(
-            "/metrics",
-            get(|| async {
-                let mut buffer = Vec::new();
-                let encoder = TextEncoder::new();
-                encoder.encode(&prometheus::gather(), &mut buffer).unwrap();
-                // return metrics
-                String::from_utf8(buffer).unwrap()
-            }),
-        )
+        // export the metrics of the layer at `/metrics`
+        .merge(metrics.routes())
         .nest("/sub", crate::sub::routes())
         .route("/", get(handler))
         .route("/hello", get(handler))
@@ -2,7 +2,8 @@
 //!
 //! ## Simple Usage
 //!
-//! Meter provider should be configured through [opentelemetry_sdk `global::set_meter_provider`](https://docs.rs/opentelemetry/0.27.1/opentelemetry/global/index.html#global-metrics-api).
+//! Meter provider should be configured through [opentelemetry_sdk `global::set_meter_provider`](https://docs.rs/opentelemetry/0.27.1/opentelemetry/global/index.html#global-metrics-api),
+//! or passed to the builder by [HttpMetricsLayerBuilder::with_provider] to keep it out of the global state.
 //! if you want to use the [prometheus exporter](https://opentelemetry.io/docs/specs/otel/metrics/sdk_exporters/prometheus/), see [Advanced Usage](#advanced-usage) below.
 //!
 //! ```
@@ -24,6 +25,7 @@
 //! }
 //! ```
 //!
+//!
 //! ## Advanced Usage
 //!
 //! this is an example to use the [prometheus exporter](https://opentelemetry.io/docs/specs/otel/metrics/sdk_exporters/prometheus/)
@@ -31,28 +33,28 @@
 //! it will export the metrics at `/metrics` endpoint
 //!
 //! ```
+//! # #[cfg(feature = "prometheus")]
+//! # fn main() {
 //! use axum_otel_metrics::HttpMetricsLayerBuilder;
 //! use axum::{response::Html, routing::get, Router};
 //!
-//! use opentelemetry::global;
 //! use opentelemetry_sdk::metrics::SdkMeterProvider;
-//! use prometheus::{Encoder, Registry, TextEncoder};
+//! use prometheus::Registry;
 //!
-//! let exporter = opentelemetry_prometheus::exporter().with_registry(prometheus::default_registry().clone()).build().unwrap();
+//! let registry = Registry::new();
+//! let exporter = opentelemetry_prometheus::exporter().with_registry(registry.clone()).build().unwrap();
 //! let provider = SdkMeterProvider::builder().with_reader(exporter).build();
-//! global::set_meter_provider(provider.clone());
 //!
-//! let metrics = HttpMetricsLayerBuilder::new().build();
+//! // the provider and the registry stay with this layer, so another app in the same
+//! // process can run its own layer with its own provider and registry
+//! let metrics = HttpMetricsLayerBuilder::new()
+//!     .with_provider(provider)
+//!     .with_prometheus_registry(registry)
+//!     .build();
 //!
 //! let app = Router::<()>::new()
 //!     // export metrics at `/metrics` endpoint
-//!     .route("/metrics", get(|| async {
-//!         let mut buffer = Vec::new();
-//!         let encoder = TextEncoder::new();
-//!         encoder.encode(&prometheus::gather(), &mut buffer).unwrap();
-//!         // return metrics
-//!         String::from_utf8(buffer).unwrap()
-//!     }))
+//!     .merge(metrics.routes())
 //!     .route("/", get(handler))
 //!     .route("/hello", get(handler))
 //!     .route("/world", get(handler))
@@ -62,44 +64,185 @@
 //! async fn handler() -> Html<&'static str> {
 //!     Html("<h1>Hello, World!</h1>")
 //! }
+//! # }
+//! # #[cfg(not(feature = "prometheus"))]
+//! # fn main() {}
+//! ```
+//!
+//! or let the builder create the prometheus exporter, the meter provider and the registry in one call:
+//!
+//! ```
+//! # #[cfg(feature = "prometheus")]
+//! # fn main() {
+//! use axum_otel_metrics::HttpMetricsLayerBuilder;
+//! use axum::Router;
+//!
+//! let (metrics, _registry) = HttpMetricsLayerBuilder::prometheus();
+//!
+//! let app = Router::<()>::new()
+//!     .merge(metrics.routes())
+//!     .layer(metrics);
+//! # }
+//! # #[cfg(not(feature = "prometheus"))]
+//! # fn main() {}
 //! ```
 
+use axum::extract::{ConnectInfo, FromRequestParts, OriginalUri};
 use axum::http::Response;
+#[cfg(feature = "prometheus")]
+use axum::response::IntoResponse;
+#[cfg(feature = "prometheus")]
+use axum::routing::get;
+#[cfg(feature = "prometheus")]
+use axum::Router;
 use axum::{extract::MatchedPath, http, http::Request};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
 use std::env;
+use std::fmt;
 use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::net::SocketAddr;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, Weak};
 use std::task::Poll::Ready;
 use std::task::{Context, Poll};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
+use opentelemetry::baggage::BaggageExt;
 use opentelemetry::global;
-use opentelemetry::metrics::{Histogram, UpDownCounter};
-use opentelemetry::KeyValue;
+use opentelemetry::metrics::{Counter, Histogram, Meter, MeterProvider, ObservableGauge, UpDownCounter};
+use opentelemetry::{KeyValue, StringValue};
+use opentelemetry_sdk::metrics::data::ResourceMetrics;
+use opentelemetry_sdk::metrics::reader::MetricReader;
+use opentelemetry_sdk::metrics::{InstrumentKind, MetricError, MetricResult, Pipeline, SdkMeterProvider, Temporality};
+use opentelemetry_sdk::Resource;
 
 use tower::{Layer, Service};
+use tower_http::classify::{ClassifiedResponse, ClassifyEos, ClassifyResponse, MakeClassifier};
 
+use bytes::Buf;
 use futures_util::ready;
 use http_body::Body as httpBody;
+use http_body::{Frame, SizeHint};
 use pin_project_lite::pin_project; // for `Body::size_hint`
+#[cfg(feature = "prometheus")]
+use prometheus::{Encoder, ProtobufEncoder, TextEncoder};
+
+/// callback to extract extra metric attributes from the request parts
+pub type RequestAttributesFn = Arc<dyn Fn(&http::request::Parts) -> Vec<KeyValue> + Send + Sync>;
+
+/// callback to extract extra metric attributes from the response parts
+pub type ResponseAttributesFn = Arc<dyn Fn(&http::response::Parts) -> Vec<KeyValue> + Send + Sync>;
+
+/// callback to classify the `User-Agent` header value into a low-cardinality category
+pub type UserAgentClassifierFn = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+/// callback to normalize the `client.address` attribute
+pub type ClientAddressNormalizerFn = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+/// callback to normalize the `server.address` attribute
+pub type ServerAddressNormalizerFn = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+/// callback to rewrite the matched route, which becomes the `http.route` attribute
+pub type RouteNormalizerFn = Arc<dyn Fn(&str) -> Cow<'_, str> + Send + Sync>;
 
 /// the metrics we used in the middleware
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct Metric {
-    pub req_duration: Histogram<f64>,
+    /// `None` if disabled by [HttpMetricsLayerBuilder::with_duration]
+    pub req_duration: Option<Histogram<f64>>,
+
+    /// the request duration histograms with custom buckets, keyed by route,
+    /// see [HttpMetricsLayerBuilder::with_route_duration_buckets]
+    pub route_req_duration: HashMap<String, Histogram<f64>>,
+
+    /// `None` if disabled by [HttpMetricsLayerBuilder::with_request_size]
+    pub req_size: Option<Histogram<u64>>,
+
+    /// `None` if disabled by [HttpMetricsLayerBuilder::with_response_size]
+    pub res_size: Option<Histogram<u64>>,
+
+    /// `None` if disabled by [HttpMetricsLayerBuilder::with_active_requests],
+    /// or in the [ActiveRequestsMode::Gauge] mode
+    pub req_active: Option<UpDownCounter<i64>>,
+
+    /// the active requests gauge, only in the [ActiveRequestsMode::Gauge] mode
+    pub req_active_gauge: Option<ObservableGauge<i64>>,
+
+    /// the active requests divided by the concurrency limit,
+    /// only when set by [HttpMetricsLayerBuilder::with_concurrency_limit]
+    pub concurrency_utilization: Option<ObservableGauge<f64>>,
+
+    /// the request counter, only when enabled by [HttpMetricsLayerBuilder::with_request_counter]
+    pub req_count: Option<Counter<u64>>,
+
+    /// the request error counter, only when enabled by [HttpMetricsLayerBuilder::with_error_counter]
+    pub req_errors: Option<Counter<u64>>,
+
+    /// the requests which met the objective of their route, only when set by [HttpMetricsLayerBuilder::with_slo]
+    pub slo_good: Option<Counter<u64>>,
+
+    /// the requests which missed the objective of their route, only when set by [HttpMetricsLayerBuilder::with_slo]
+    pub slo_bad: Option<Counter<u64>>,
+
+    /// counts the requests which never produce a response, i.e. the inner service returned an error
+    pub req_failures: Counter<u64>,
+
+    /// the duration of the skipped metrics endpoint scrapes,
+    /// only when enabled by [HttpMetricsLayerBuilder::with_self_observe]
+    pub scrape_duration: Option<Histogram<f64>>,
+
+    /// the time to first byte of the response body, only when enabled by [HttpMetricsLayerBuilder::with_ttfb]
+    pub res_ttfb: Option<Histogram<f64>>,
 
-    pub req_size: Histogram<u64>,
+    /// the request header size, only when enabled by [HttpMetricsLayerBuilder::with_header_size_metric]
+    pub req_header_size: Option<Histogram<u64>>,
 
-    pub res_size: Histogram<u64>,
+    /// the duration of the upgraded requests, only in the [WsMode::Separate] mode
+    pub upgrade_duration: Option<Histogram<f64>>,
 
-    pub req_active: UpDownCounter<i64>,
+    /// the duration of the server-sent events streams, only in the [SseMode::Stream] mode
+    pub stream_duration: Option<Histogram<f64>>,
+
+    /// the CPU time spent polling the requests, only when enabled by `HttpMetricsLayerBuilder::with_cpu_time_metric`
+    pub req_cpu_time: Option<Histogram<f64>>,
+
+    /// the time until the inner service returns the response headers,
+    /// only when enabled by [HttpMetricsLayerBuilder::with_phase_breakdown]
+    pub handler_duration: Option<Histogram<f64>>,
+
+    /// the time from the first to the last chunk of the response body,
+    /// only when enabled by [HttpMetricsLayerBuilder::with_phase_breakdown]
+    pub res_body_duration: Option<Histogram<f64>>,
+
+    /// the duration of the requests without a matched route,
+    /// only when enabled by [HttpMetricsLayerBuilder::with_separate_unmatched_instrument]
+    pub unmatched_duration: Option<Histogram<f64>>,
+
+    /// the request size of the requests without a matched route,
+    /// only when enabled by [HttpMetricsLayerBuilder::with_separate_unmatched_instrument]
+    pub unmatched_req_size: Option<Histogram<u64>>,
+
+    /// the response size of the requests without a matched route,
+    /// only when enabled by [HttpMetricsLayerBuilder::with_separate_unmatched_instrument]
+    pub unmatched_res_size: Option<Histogram<u64>>,
+
+    /// counts the request durations clamped to the cap set by [HttpMetricsLayerBuilder::with_max_recorded_duration]
+    pub duration_clamped: Option<Counter<u64>>,
+
+    /// the time spent waiting for the inner service to become ready,
+    /// only when enabled by [HttpMetricsLayerBuilder::with_poll_ready_metric]
+    pub poll_ready_duration: Option<Histogram<f64>>,
 }
 
 #[derive(Clone)]
 pub struct MetricState {
     /// hold the metrics we used in the middleware
-    pub metric: Metric,
+    pub metric: Arc<Metric>,
 
     /// PathSkipper used to skip some paths for not recording metrics
     skipper: PathSkipper,
@@ -109,24 +252,398 @@ pub struct MetricState {
     /// because there is no way to get the scheme from the request in http server
     /// (except for absolute uri request, but which is only used when as a proxy server).
     is_tls: bool,
+
+    /// the `http.route` value recorded for requests without a matched route
+    unmatched_route_label: String,
+
+    /// extract extra attributes from the request, which are appended to the histograms' attributes
+    attributes_from_request: Option<RequestAttributesFn>,
+
+    /// extract extra attributes from the response, which are appended to the histograms' attributes
+    attributes_from_response: Option<ResponseAttributesFn>,
+
+    /// whether to record the `http.response.status_code` attribute
+    status_code_attribute: bool,
+
+    /// whether to record the `http.response.status_class` attribute, e.g. `2xx`, `5xx`
+    status_class_attribute: bool,
+
+    /// whether to count the bytes actually sent in the response body,
+    /// instead of relying on the body's size hint
+    exact_response_size: bool,
+
+    /// whether to count the bytes actually received in the request body,
+    /// instead of relying on the `Content-Length` header
+    exact_request_size: bool,
+
+    /// whether to record the `server.port` attribute
+    server_port_attribute: bool,
+
+    /// whether to record the `network.protocol.version` attribute
+    protocol_version_attribute: bool,
+
+    /// classify the `User-Agent` header into the `user_agent.category` attribute
+    user_agent_classifier: Option<UserAgentClassifierFn>,
+
+    /// the unit of the request duration
+    duration_unit: DurationUnit,
+
+    /// which responses are counted by the error counter
+    error_counter: Option<ErrorKind>,
+
+    /// where to get the `client.address` attribute from
+    client_address: Option<ClientAddrSource>,
+
+    /// normalize the `client.address` attribute, e.g. to bucket by subnet
+    client_address_normalizer: Option<ClientAddressNormalizerFn>,
+
+    /// whether to record the `server.address` attribute
+    server_address_attribute: bool,
+
+    /// normalize the `server.address` attribute, e.g. to map the hosts to a known set
+    server_address_normalizer: Option<ServerAddressNormalizerFn>,
+
+    /// rewrite the matched route before it is used for the skip check and the `http.route` attribute
+    route_normalizer: Option<RouteNormalizerFn>,
+
+    /// the `server.address` value recorded for requests without a `Host` header
+    server_address_fallback: String,
+
+    /// whether to trust the `X-Forwarded-*` headers to determine the `url.scheme` attribute
+    trust_forwarded_headers: bool,
+
+    /// the number of the in-flight requests, see [HttpMetricsLayer::active_requests]
+    active_requests: Arc<AtomicI64>,
+
+    /// which attribute carries the route
+    route_mode: RouteMode,
+
+    /// whether the `http.target` attribute includes the query string
+    include_query_string: bool,
+
+    /// whether to record the `http.request.content_type` attribute
+    request_content_type_attribute: bool,
+
+    /// whether to record the `http.response.content_type` attribute
+    response_content_type_attribute: bool,
+
+    /// the content types recorded as is, others are recorded as `other`
+    content_type_allow_list: Option<Vec<String>>,
+
+    /// whether to record the `rpc.grpc.status_code` attribute
+    grpc_status_attribute: bool,
+
+    /// whether to record the `client.port` attribute
+    client_port_attribute: bool,
+
+    /// the ratio of the requests recorded into the histograms and counters
+    sampling_ratio: f64,
+
+    /// whether to record the non-standard methods as `_OTHER`
+    method_normalization: bool,
+
+    /// RequestSkipper used to skip some requests before any metric is recorded
+    request_skipper: Option<RequestSkipper>,
+
+    /// whether the active requests carry the `http.route` attribute
+    active_requests_route_label: bool,
+
+    /// the attributes added to every metric, see [HttpMetricsLayerBuilder::with_constant_labels]
+    constant_labels: Vec<KeyValue>,
+
+    /// the service level objectives keyed by route, see [HttpMetricsLayerBuilder::with_slo]
+    slos: HashMap<String, SloObjective>,
+
+    /// whether to record the `http.connection.reused` attribute from the [ConnectionReused] extension
+    connection_reused_attribute: bool,
+
+    /// drops attributes per instrument, see [HttpMetricsLayerBuilder::with_attribute_filter]
+    attribute_filter: Option<AttributeFilterFn>,
+
+    /// how the upgraded responses are recorded, see [HttpMetricsLayerBuilder::with_websocket_handling]
+    websocket_mode: WsMode,
+
+    /// the header of the `http.request.resend_count` attribute, see [HttpMetricsLayerBuilder::with_resend_count_header]
+    resend_count_header: Option<http::HeaderName>,
+
+    /// how the server-sent events responses are recorded, see [HttpMetricsLayerBuilder::with_sse_handling]
+    sse_mode: SseMode,
+
+    /// the classifier deciding which responses failed, see [HttpMetricsLayerBuilder::with_classifier]
+    classifier: Option<Arc<dyn DynMakeClassifier>>,
+
+    /// whether to record the `error.type` attribute of the failed requests
+    error_type_attribute: bool,
+
+    /// whether only the failed requests get the full attributes
+    detailed_attributes_on_error_only: bool,
+
+    /// whether to record the `url.scheme` attribute of the active requests
+    scheme_attribute: bool,
+
+    /// whether the requests without a matched route are recorded into their own instruments
+    separate_unmatched_instrument: bool,
+
+    /// whether to record the `http.route.original` attribute
+    original_route_attribute: bool,
+
+    /// the attributes of the active requests counter
+    active_requests_attributes: ActiveRequestsAttributes,
+
+    /// the cap of the recorded request durations
+    max_recorded_duration: Option<Duration>,
+
+    /// whether the body sizes are only recorded for the successful responses
+    size_metrics_on_success_only: bool,
+
+    /// the extra attributes of the matched routes
+    route_attributes: HashMap<String, Vec<KeyValue>>,
+
+    /// the baggage entries recorded as attributes, see [HttpMetricsLayerBuilder::with_baggage_attributes]
+    baggage_attributes: Vec<opentelemetry::Key>,
+
+    /// whether the [MetricHandles] are inserted into the request extensions
+    metric_handles_extension: bool,
+}
+
+/// the [Metric] handles of the layer, available to the handlers as an extractor
+/// when [HttpMetricsLayerBuilder::with_metric_handles_extension] is enabled
+///
+/// ```
+/// use axum_otel_metrics::MetricHandles;
+/// use opentelemetry::KeyValue;
+///
+/// async fn handler(metrics: MetricHandles) -> &'static str {
+///     if let Some(req_duration) = &metrics.req_duration {
+///         req_duration.record(0.5, &[KeyValue::new("http.route", "/batch")]);
+///     }
+///     "ok"
+/// }
+/// ```
+///
+/// the recorded values are mixed with the ones of the middleware, so use the same attributes as it does.
+/// every distinct attribute set creates a new time series, never put unbounded values (e.g. ids) in them.
+#[derive(Clone, Debug)]
+pub struct MetricHandles(pub Arc<Metric>);
+
+impl std::ops::Deref for MetricHandles {
+    type Target = Metric;
+
+    fn deref(&self) -> &Metric {
+        &self.0
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for MetricHandles {
+    type Rejection = (http::StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut http::request::Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts.extensions.get::<MetricHandles>().cloned().ok_or((
+            http::StatusCode::INTERNAL_SERVER_ERROR,
+            "the HttpMetricsLayer is missing or its metric handles extension is disabled",
+        ))
+    }
 }
 
 /// the service wrapper
-#[derive(Clone)]
 pub struct HttpMetrics<S> {
-    pub(crate) state: MetricState,
+    /// shared with every [ResponseFuture], so cloning it per request is a refcount bump
+    pub(crate) state: Arc<MetricState>,
 
     /// inner service which is wrapped by this middleware
     service: S,
+
+    /// when `poll_ready` first returned pending, only tracked for the `http.server.poll_ready.duration` histogram
+    pending_since: Option<Instant>,
+}
+
+/// a clone is a fresh service, it does not inherit the pending `poll_ready` wait
+impl<S: Clone> Clone for HttpMetrics<S> {
+    fn clone(&self) -> Self {
+        HttpMetrics {
+            state: self.state.clone(),
+            service: self.service.clone(),
+            pending_since: None,
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct HttpMetricsLayer {
     /// the metric state, use both by the middleware handler and metrics export endpoint
-    pub(crate) state: MetricState,
+    pub(crate) state: Arc<MetricState>,
+
+    /// the prometheus registry exported by [HttpMetricsLayer::routes]
+    #[cfg(feature = "prometheus")]
+    registry: Option<prometheus::Registry>,
+
+    /// the exposition format of [HttpMetricsLayer::routes]
+    #[cfg(feature = "prometheus")]
+    metrics_format: MetricsFormat,
+
+    /// the meter provider created by the builder from [HttpMetricsLayerBuilder::with_metric_reader],
+    /// kept alive here since dropping the last reference to it shuts down the readers
+    provider: Option<SdkMeterProvider>,
+}
+
+impl fmt::Debug for HttpMetricsLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut f = f.debug_struct("HttpMetricsLayer");
+        f.field("metric", &self.state.metric).field("skipper", &self.state.skipper);
+        #[cfg(feature = "prometheus")]
+        f.field("registry", &self.registry)
+            .field("metrics_format", &self.metrics_format);
+        f.field("provider", &self.provider).finish_non_exhaustive()
+    }
+}
+
+impl HttpMetricsLayer {
+    /// the [Metric] handles of the layer, the same ones handed to the handlers by the [MetricHandles] extractor
+    pub fn metric_handles(&self) -> MetricHandles {
+        MetricHandles(self.state.metric.clone())
+    }
+
+    /// flush the metrics of the meter provider retained by the layer, i.e. the provider set by
+    /// [HttpMetricsLayerBuilder::with_provider], or created from [HttpMetricsLayerBuilder::with_metric_reader]
+    /// or `HttpMetricsLayerBuilder::prometheus`.
+    ///
+    /// it is a no-op when the layer uses the global meter provider.
+    pub fn force_flush(&self) -> Result<(), MetricError> {
+        match &self.provider {
+            Some(provider) => provider.force_flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// returns a [ShutdownHandle] to flush and shut down the meter provider retained by the layer
+    /// when the server exits, see [HttpMetricsLayer::force_flush] for which provider is retained.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            provider: self.provider.clone(),
+        }
+    }
+
+    /// returns the number of the in-flight requests handled by this layer,
+    /// a cheap in-process read which does not go through the OpenTelemetry pipeline.
+    pub fn active_requests(&self) -> i64 {
+        self.state.active_requests.load(Ordering::Relaxed)
+    }
+
+    /// returns a [Router] which exports the metrics at `/metrics` endpoint, in the prometheus text
+    /// or protobuf format, see [HttpMetricsLayerBuilder::with_metrics_format].
+    ///
+    /// the registry set by [HttpMetricsLayerBuilder::with_prometheus_registry] is exported,
+    /// or the prometheus default registry if it is not set.
+    #[cfg(feature = "prometheus")]
+    pub fn routes<S>(&self) -> Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        let registry = self
+            .registry
+            .clone()
+            .unwrap_or_else(|| prometheus::default_registry().clone());
+        let metrics_format = self.metrics_format;
+        Router::new().route(
+            METRICS_PATH,
+            get(move |headers: http::HeaderMap| async move {
+                let protobuf = match metrics_format {
+                    MetricsFormat::Text => false,
+                    MetricsFormat::Protobuf => true,
+                    MetricsFormat::Negotiate => accepts_protobuf(&headers),
+                };
+                if protobuf {
+                    encode_registry(&registry, ProtobufEncoder::new())
+                } else {
+                    encode_registry(&registry, TextEncoder::new())
+                }
+            }),
+        )
+    }
+}
+
+/// flushes and shuts down the meter provider of a [HttpMetricsLayer] on the server exit,
+/// so the last interval of the metrics is not lost.
+///
+/// ```no_run
+/// # async fn run(metrics: axum_otel_metrics::HttpMetricsLayer) {
+/// let shutdown = metrics.shutdown_handle();
+/// tokio::signal::ctrl_c().await.unwrap();
+/// // the exporters may block, keep them off the async worker threads
+/// let flushed = shutdown.clone();
+/// tokio::task::spawn_blocking(move || flushed.flush()).await.unwrap().unwrap();
+/// shutdown.shutdown().unwrap();
+/// # }
+/// ```
+///
+/// both are no-ops when the layer uses the global meter provider.
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    provider: Option<SdkMeterProvider>,
+}
+
+impl ShutdownHandle {
+    /// export the pending metrics, it blocks until the exporters are done,
+    /// so from async code, run it on a blocking thread, e.g. with `tokio::task::spawn_blocking`.
+    pub fn flush(&self) -> Result<(), MetricError> {
+        match &self.provider {
+            Some(provider) => provider.force_flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// shut down the meter provider, the metrics recorded afterwards are dropped
+    pub fn shutdown(&self) -> Result<(), MetricError> {
+        match &self.provider {
+            Some(provider) => provider.shutdown(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// a handler exporting `prometheus::default_registry()` in prometheus text format,
+/// with the `Content-Type: text/plain; version=0.0.4` header.
+///
+/// the metrics of the layer are exported by [HttpMetricsLayer::routes], which should be preferred.
+/// this handler is an escape hatch for the metrics registered into the prometheus default registry
+/// outside of this crate, e.g. by the `prometheus` macros, it does not export the layer's own registry.
+///
+/// ```
+/// use axum::{routing::get, Router};
+/// use axum_otel_metrics::default_registry_metrics_handler;
+///
+/// let app: Router = Router::new().route("/default-metrics", get(default_registry_metrics_handler));
+/// ```
+#[cfg(feature = "prometheus")]
+pub async fn default_registry_metrics_handler() -> impl IntoResponse {
+    encode_registry(prometheus::default_registry(), TextEncoder::new())
+}
+
+/// whether the `Accept` header of a scrape asks for the prometheus protobuf format
+#[cfg(feature = "prometheus")]
+fn accepts_protobuf(headers: &http::HeaderMap) -> bool {
+    headers
+        .get_all(http::header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|media_range| media_range.trim().to_ascii_lowercase())
+        .any(|media_range| {
+            media_range.starts_with("application/vnd.google.protobuf")
+                && media_range.contains("proto=io.prometheus.client.metricfamily")
+        })
+}
+
+/// encode the metrics in the registry with the given prometheus encoder
+#[cfg(feature = "prometheus")]
+fn encode_registry(registry: &prometheus::Registry, encoder: impl Encoder) -> axum::response::Response {
+    let mut buffer = Vec::new();
+    match encoder.encode(&registry.gather(), &mut buffer) {
+        Ok(()) => ([(http::header::CONTENT_TYPE, encoder.format_type().to_string())], buffer).into_response(),
+        Err(err) => (http::StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
 }
 
-// TODO support custom buckets
 // allocation not allowed in statics: static HTTP_REQ_DURATION_HISTOGRAM_BUCKETS: Vec<f64> = vec![0, 0.005, 0.01, 0.025, 0.05, 0.075, 0.1, 0.25, 0.5, 0.75, 1, 2.5, 5, 7.5, 10];
 // as https://github.com/open-telemetry/semantic-conventions/blob/main/docs/http/http-metrics.md#metric-httpserverrequestduration spec
 // This metric SHOULD be specified with ExplicitBucketBoundaries of [ 0, 0.005, 0.01, 0.025, 0.05, 0.075, 0.1, 0.25, 0.5, 0.75, 1, 2.5, 5, 7.5, 10 ].
@@ -138,6 +655,18 @@ const HTTP_REQ_DURATION_HISTOGRAM_BUCKETS: &[f64] = &[
 const KB: f64 = 1024.0;
 const MB: f64 = 1024.0 * KB;
 
+const HTTP_REQ_HEADER_SIZE_HISTOGRAM_BUCKETS: &[f64] = &[
+    256.0,     // 256 B
+    512.0,     // 512 B
+    1.0 * KB,  // 1 KB
+    2.0 * KB,  // 2 KB
+    4.0 * KB,  // 4 KB
+    8.0 * KB,  // 8 KB
+    16.0 * KB, // 16 KB
+    32.0 * KB, // 32 KB
+    64.0 * KB, // 64 KB
+];
+
 const HTTP_REQ_SIZE_HISTOGRAM_BUCKETS: &[f64] = &[
     1.0 * KB,   // 1 KB
     2.0 * KB,   // 2 KB
@@ -151,423 +680,6480 @@ const HTTP_REQ_SIZE_HISTOGRAM_BUCKETS: &[f64] = &[
     10.0 * MB,  // 10 MB
 ];
 
-/// A helper that instructs the metrics layer to ignore
-/// certain paths.
-///
-/// The [HttpMetricsLayerBuilder] uses this helper during the
-/// construction of the [HttpMetricsLayer] that will be called
-/// by Axum / Hyper / Tower when a request comes in.
-#[derive(Clone)]
-pub struct PathSkipper {
-    skip: Arc<dyn Fn(&str) -> bool + 'static + Send + Sync>,
+/// callback deciding whether to skip a request by its method and path,
+/// see [PathSkipper::new_with_method]
+pub type MethodSkipFn = Arc<dyn Fn(&http::Method, &str) -> bool + 'static + Send + Sync>;
+
+/// callback of [RequestSkipper], whether to skip the request
+pub type RequestSkipFn = Arc<dyn Fn(&http::request::Parts) -> bool + Send + Sync>;
+
+/// callback of [HttpMetricsLayerBuilder::with_attribute_filter], called with the instrument name
+/// and an attribute, whether to keep the attribute on the instrument
+pub type AttributeFilterFn = Arc<dyn Fn(&str, &KeyValue) -> bool + Send + Sync>;
+
+/// the unit of the request duration histogram
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DurationUnit {
+    /// record the duration in seconds, as the semantic conventions
+    #[default]
+    Seconds,
+    /// record the duration in milliseconds
+    Milliseconds,
 }
 
-impl PathSkipper {
-    /// Returns a [PathSkipper] that skips recording metrics
-    /// for requests whose path, when passed to `fn`, returns
-    /// `true`.
-    ///
-    /// Only static functions are accepted -- callables such
-    /// as closures that capture their surrounding context will
-    /// not work here.  For a variant that works, consult the
-    /// [PathSkipper::new_with_fn] method.
-    pub fn new(skip: fn(&str) -> bool) -> Self {
-        Self { skip: Arc::new(skip) }
+impl DurationUnit {
+    /// the UCUM unit symbol
+    fn symbol(self) -> &'static str {
+        match self {
+            DurationUnit::Seconds => "s",
+            DurationUnit::Milliseconds => "ms",
+        }
     }
 
-    /// Dynamic variant of [PathSkipper::new].
-    ///
-    /// This variant requires the callable to be wrapped in an
-    /// [Arc] but, in exchange for this requirement, the caller
-    /// can use closures that capture variables from their context.
-    ///
-    /// The callable argument *must be thread-safe*.  You, as
-    /// the implementor and user of this code, have that
-    /// responsibility.
-    pub fn new_with_fn(skip: Arc<dyn Fn(&str) -> bool + 'static + Send + Sync>) -> Self {
-        Self { skip }
+    fn name(self) -> &'static str {
+        match self {
+            DurationUnit::Seconds => "seconds",
+            DurationUnit::Milliseconds => "milliseconds",
+        }
     }
-}
 
-impl Default for PathSkipper {
-    /// Returns a `PathSkipper` that skips any path which
-    /// starts with `/metrics` or `/favicon.ico``.
-    ///
-    /// This is the default implementation used when
-    /// building an HttpMetricsLayerBuilder from scratch.
-    fn default() -> Self {
-        Self::new(|s| s.starts_with("/metrics") || s.starts_with("/favicon.ico"))
+    /// how many of this unit in one second
+    fn per_second(self) -> f64 {
+        match self {
+            DurationUnit::Seconds => 1.0,
+            DurationUnit::Milliseconds => 1000.0,
+        }
     }
 }
 
-#[derive(Clone, Default)]
-pub struct HttpMetricsLayerBuilder {
-    skipper: PathSkipper,
-    is_tls: bool,
+/// which responses are counted as errors by [HttpMetricsLayerBuilder::with_error_counter]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// only server errors (5xx)
+    ServerOnly,
+    /// both client errors (4xx) and server errors (5xx)
+    ClientAndServer,
 }
 
-impl HttpMetricsLayerBuilder {
-    pub fn new() -> Self {
-        HttpMetricsLayerBuilder::default()
-    }
-
-    pub fn with_skipper(mut self, skipper: PathSkipper) -> Self {
-        self.skipper = skipper;
-        self
+impl ErrorKind {
+    fn is_error(self, status: http::StatusCode) -> bool {
+        match self {
+            ErrorKind::ServerOnly => status.is_server_error(),
+            ErrorKind::ClientAndServer => status.is_client_error() || status.is_server_error(),
+        }
     }
+}
 
-    pub fn build(self) -> HttpMetricsLayer {
-        let provider = global::meter_provider();
-        let meter = provider.meter_with_scope(
-            opentelemetry::InstrumentationScope::builder(env!("CARGO_PKG_NAME"))
-                .with_version(env!("CARGO_PKG_VERSION"))
-                .build(),
-        );
+/// a [MakeClassifier] with its types erased, see [HttpMetricsLayerBuilder::with_classifier]
+trait DynMakeClassifier: Send + Sync {
+    fn make_classifier(&self, req: &Request<()>) -> Box<dyn DynClassifyResponse>;
+}
 
-        // request_duration_seconds
-        let req_duration = meter
-            .f64_histogram("http.server.request.duration")
-            .with_unit("s")
-            .with_description("The HTTP request latencies in seconds.")
-            .with_boundaries(HTTP_REQ_DURATION_HISTOGRAM_BUCKETS.to_vec())
-            .build();
+impl<M> DynMakeClassifier for M
+where
+    M: MakeClassifier + Send + Sync,
+    M::Classifier: Send + 'static,
+    M::ClassifyEos: Send + 'static,
+    M::FailureClass: fmt::Display,
+{
+    fn make_classifier(&self, req: &Request<()>) -> Box<dyn DynClassifyResponse> {
+        Box::new(MakeClassifier::make_classifier(self, req))
+    }
+}
 
-        // request_size_bytes
-        let req_size = meter
-            .u64_histogram("http.server.request.size")
-            .with_unit("By")
-            .with_description("The HTTP request sizes in bytes.")
-            .with_boundaries(HTTP_REQ_SIZE_HISTOGRAM_BUCKETS.to_vec())
-            .build();
+/// a [ClassifyResponse] with its types erased, the failure class is formatted as the `error.type` value
+trait DynClassifyResponse: Send {
+    fn classify_response(self: Box<Self>, res: &Response<()>) -> Classification;
+}
 
-        let res_size = meter
-            .u64_histogram("http.server.response.size")
-            .with_unit("By")
-            .with_description("The HTTP response sizes in bytes.")
-            .with_boundaries(HTTP_REQ_SIZE_HISTOGRAM_BUCKETS.to_vec())
-            .build();
+impl<C> DynClassifyResponse for C
+where
+    C: ClassifyResponse + Send,
+    C::ClassifyEos: Send + 'static,
+    C::FailureClass: fmt::Display,
+{
+    fn classify_response(self: Box<Self>, res: &Response<()>) -> Classification {
+        match ClassifyResponse::classify_response(*self, res) {
+            ClassifiedResponse::Ready(result) => Classification::Ready(result.err().map(|class| class.to_string())),
+            ClassifiedResponse::RequiresEos(eos) => Classification::RequiresEos(Box::new(eos)),
+        }
+    }
+}
 
-        // no u64_up_down_counter because up_down_counter maybe < 0 since it allow negative values
-        let req_active = meter
-            .i64_up_down_counter("http.server.active_requests")
-            .with_description("The number of active HTTP requests.")
-            .build();
+/// the classification of the response head, the failure class if it failed
+enum Classification {
+    Ready(Option<String>),
+    RequiresEos(Box<dyn DynClassifyEos>),
+}
 
-        let meter_state = MetricState {
-            metric: Metric {
-                req_duration,
-                req_size,
-                res_size,
-                req_active,
-            },
-            skipper: self.skipper,
-            is_tls: self.is_tls,
-        };
+/// a [ClassifyEos] with its types erased
+trait DynClassifyEos: Send {
+    fn classify_eos(self: Box<Self>, trailers: Option<&http::HeaderMap>) -> Option<String>;
+}
 
-        HttpMetricsLayer { state: meter_state }
+impl<E> DynClassifyEos for E
+where
+    E: ClassifyEos + Send,
+    E::FailureClass: fmt::Display,
+{
+    fn classify_eos(self: Box<Self>, trailers: Option<&http::HeaderMap>) -> Option<String> {
+        ClassifyEos::classify_eos(*self, trailers)
+            .err()
+            .map(|class| class.to_string())
     }
 }
 
-impl<S> Layer<S> for HttpMetricsLayer {
-    type Service = HttpMetrics<S>;
+/// the attributes of the `http.server.active_requests` counter,
+/// see [HttpMetricsLayerBuilder::with_active_requests_attributes]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ActiveRequestsAttributes {
+    /// `http.request.method` and `url.scheme`
+    #[default]
+    MethodScheme,
+    /// `http.request.method` only
+    MethodOnly,
+    /// neither of them, only the route and the constant labels if any
+    None,
+}
 
-    fn layer(&self, service: S) -> Self::Service {
-        HttpMetrics {
-            state: self.state.clone(),
-            service,
+/// the error returned by [HttpMetricsLayerBuilder::try_build]
+#[derive(Debug)]
+pub enum HttpMetricsError {
+    /// the histogram buckets are empty, not finite, or not strictly increasing
+    InvalidBuckets,
+    /// the prometheus exporter failed to register to the registry,
+    /// e.g. the registry already has an exporter registered
+    Registry(MetricError),
+}
+
+impl std::fmt::Display for HttpMetricsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HttpMetricsError::InvalidBuckets => {
+                write!(f, "histogram buckets must be non-empty, finite and strictly increasing")
+            }
+            HttpMetricsError::Registry(err) => write!(f, "failed to register the prometheus exporter: {err}"),
         }
     }
 }
 
-pin_project! {
+impl std::error::Error for HttpMetricsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HttpMetricsError::InvalidBuckets => None,
+            HttpMetricsError::Registry(err) => Some(err),
+        }
+    }
+}
+
+/// the default buckets of the request duration histogram in seconds,
+/// as recommended by the semantic conventions.
+///
+/// useful to base the buckets set by [HttpMetricsLayerBuilder::with_duration_buckets] on the defaults.
+pub fn default_duration_buckets() -> Vec<f64> {
+    HTTP_REQ_DURATION_HISTOGRAM_BUCKETS.to_vec()
+}
+
+/// the default buckets of the request and response size histograms in bytes.
+///
+/// useful to base the buckets set by [HttpMetricsLayerBuilder::with_size_buckets] on the defaults.
+pub fn default_size_buckets() -> Vec<f64> {
+    HTTP_REQ_SIZE_HISTOGRAM_BUCKETS.to_vec()
+}
+
+/// whether the histogram buckets are non-empty, finite and strictly increasing
+fn valid_buckets(buckets: &[f64]) -> bool {
+    !buckets.is_empty() && buckets.iter().all(|b| b.is_finite()) && buckets.windows(2).all(|w| w[0] < w[1])
+}
+
+/// how the active requests are recorded, see [HttpMetricsLayerBuilder::with_active_requests_mode]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ActiveRequestsMode {
+    /// an `UpDownCounter` with the `http.request.method` and `url.scheme` attributes,
+    /// incremented and decremented for each request
+    #[default]
+    UpDownCounter,
+    /// an observable gauge with only the constant labels, reporting the in-flight requests when the metrics are collected
+    Gauge,
+}
+
+/// the exposition format of [HttpMetricsLayer::routes], see [HttpMetricsLayerBuilder::with_metrics_format]
+#[cfg(feature = "prometheus")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MetricsFormat {
+    /// the protobuf format if the `Accept` header of the scrape asks for it, otherwise the text format
+    #[default]
+    Negotiate,
+    /// always the prometheus text format
+    Text,
+    /// always the prometheus protobuf (delimited `MetricFamily`) format
+    Protobuf,
+}
+
+/// the class of a response status code, see [HttpMetricsLayerBuilder::with_slo]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatusClass {
+    /// 1xx
+    Informational,
+    /// 2xx
+    Success,
+    /// 3xx
+    Redirection,
+    /// 4xx
+    ClientError,
+    /// 5xx
+    ServerError,
+}
+
+impl StatusClass {
+    fn of(status: http::StatusCode) -> Self {
+        match status.as_u16() {
+            100..=199 => StatusClass::Informational,
+            200..=299 => StatusClass::Success,
+            300..=399 => StatusClass::Redirection,
+            400..=499 => StatusClass::ClientError,
+            _ => StatusClass::ServerError,
+        }
+    }
+}
+
+/// a latency and status objective of a route, see [HttpMetricsLayerBuilder::with_slo]
+#[derive(Clone, Debug)]
+struct SloObjective {
+    latency_threshold: Duration,
+    good_status_classes: Vec<StatusClass>,
+}
+
+/// how the upgraded (`101 Switching Protocols`) responses, e.g. WebSocket, are recorded,
+/// see [HttpMetricsLayerBuilder::with_websocket_handling]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WsMode {
+    /// skip the duration, size and time to first byte histograms, only the counters are recorded
+    #[default]
+    Skip,
+    /// record the duration into the `http.server.upgrade.duration` histogram instead,
+    /// the size and time to first byte histograms are skipped
+    Separate,
+    /// record them like any other response
+    Record,
+}
+
+/// overrides of the descriptions and units of the instruments, see [HttpMetricsLayerBuilder::with_instrument_metadata]
+///
+/// the instruments are named without the prefix of [HttpMetricsLayerBuilder::with_metric_prefix],
+/// e.g. `http.server.request.duration`, which also covers the histograms of
+/// [HttpMetricsLayerBuilder::with_route_duration_buckets].
+#[derive(Clone, Debug, Default)]
+pub struct InstrumentMetadata {
+    descriptions: HashMap<String, String>,
+    units: HashMap<String, String>,
+}
+
+impl InstrumentMetadata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// override the description of the instrument
+    pub fn with_description(mut self, instrument: &str, description: impl Into<String>) -> Self {
+        self.descriptions.insert(instrument.to_string(), description.into());
+        self
+    }
+
+    /// override the unit of the instrument, e.g. `ms`.
+    ///
+    /// the recorded values are not converted, see [HttpMetricsLayerBuilder::with_duration_unit] for the durations.
+    pub fn with_unit(mut self, instrument: &str, unit: impl Into<String>) -> Self {
+        self.units.insert(instrument.to_string(), unit.into());
+        self
+    }
+
+    fn description(&self, instrument: &str, default: impl Into<String>) -> String {
+        self.descriptions.get(instrument).cloned().unwrap_or_else(|| default.into())
+    }
+
+    fn unit(&self, instrument: &str, default: impl Into<String>) -> String {
+        self.units.get(instrument).cloned().unwrap_or_else(|| default.into())
+    }
+}
+
+/// how the server-sent events (`Content-Type: text/event-stream`) responses are recorded,
+/// see [HttpMetricsLayerBuilder::with_sse_handling]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SseMode {
+    /// record them like any other response, the duration is the time to the response headers
+    #[default]
+    Record,
+    /// skip the duration, size and time to first byte histograms, only the counters are recorded
+    Skip,
+    /// record the time until the end of the stream into the `http.server.stream.duration` histogram
+    /// instead of the request duration, the sizes are recorded as usual
+    Stream,
+}
+
+/// which attribute carries the route, see [HttpMetricsLayerBuilder::with_route_attribute_mode]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RouteMode {
+    /// the matched route template in the `http.route` attribute, e.g. `/users/{id}`
+    #[default]
+    Template,
+    /// the raw request path in the `http.target` attribute, e.g. `/users/42`,
+    /// the full path from the axum `OriginalUri` in a nested router
+    RawPath,
+    /// both the `http.route` and the `http.target` attributes
+    Both,
+}
+
+/// whether the request came in on a reused (keep-alive) connection,
+/// a request extension read by [HttpMetricsLayerBuilder::with_connection_reused_attribute].
+///
+/// neither axum nor hyper expose it, so it has to be inserted by a connection-level service,
+/// e.g. one counting the requests it served per connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectionReused(pub bool);
+
+/// whether the request came in on a TLS connection, a request extension deciding the `url.scheme` attribute.
+///
+/// it takes precedence over [HttpMetricsLayerBuilder::with_is_tls] and the `X-Forwarded-*` headers,
+/// so a server accepting both plaintext and TLS connections (e.g. with a rustls based acceptor)
+/// can insert it per connection to get the accurate scheme.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TlsConnection(pub bool);
+
+/// where to get the `client.address` attribute from, see [HttpMetricsLayerBuilder::with_client_address]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientAddrSource {
+    /// the first address of the `X-Forwarded-For` header
+    ForwardedFor,
+    /// the `X-Real-IP` header
+    RealIp,
+    /// the peer address of the connection,
+    /// requires the app to be served with [axum::Router::into_make_service_with_connect_info]
+    ConnectInfo,
+}
+
+impl ClientAddrSource {
+    fn resolve<T>(self, req: &Request<T>) -> Option<String> {
+        let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+        match self {
+            ClientAddrSource::ForwardedFor => header("X-Forwarded-For")
+                .and_then(|v| v.split(',').next())
+                .map(|v| v.trim().to_string()),
+            ClientAddrSource::RealIp => header("X-Real-IP").map(|v| v.trim().to_string()),
+            ClientAddrSource::ConnectInfo => req
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip().to_string()),
+        }
+        .filter(|addr| !addr.is_empty())
+    }
+}
+
+/// A helper that instructs the metrics layer to ignore
+/// certain paths.
+///
+/// The [HttpMetricsLayerBuilder] uses this helper during the
+/// construction of the [HttpMetricsLayer] that will be called
+/// by Axum / Hyper / Tower when a request comes in.
+///
+/// The clones share the callable, so it can be swapped at runtime
+/// with [PathSkipper::replace_fn] after the layer is built.
+#[derive(Clone)]
+pub struct PathSkipper {
+    skip: Arc<RwLock<MethodSkipFn>>,
+}
+
+impl PathSkipper {
+    /// Returns a [PathSkipper] that skips recording metrics
+    /// for requests whose path, when passed to `fn`, returns
+    /// `true`.
+    ///
+    /// Only static functions are accepted -- callables such
+    /// as closures that capture their surrounding context will
+    /// not work here.  For a variant that works, consult the
+    /// [PathSkipper::new_with_fn] method.
+    pub fn new(skip: fn(&str) -> bool) -> Self {
+        Self::new_with_method(Arc::new(move |_: &http::Method, path: &str| skip(path)))
+    }
+
+    /// Dynamic variant of [PathSkipper::new].
+    ///
+    /// This variant requires the callable to be wrapped in an
+    /// [Arc] but, in exchange for this requirement, the caller
+    /// can use closures that capture variables from their context.
+    ///
+    /// The callable argument *must be thread-safe*.  You, as
+    /// the implementor and user of this code, have that
+    /// responsibility.
+    pub fn new_with_fn(skip: Arc<dyn Fn(&str) -> bool + 'static + Send + Sync>) -> Self {
+        Self::new_with_method(Arc::new(move |_: &http::Method, path: &str| skip(path)))
+    }
+
+    /// Method-aware variant of [PathSkipper::new_with_fn].
+    ///
+    /// The callable receives both the request method and the
+    /// path, so that e.g. `OPTIONS` preflight requests can be
+    /// skipped without skipping the `GET` requests to the same
+    /// path.
+    pub fn new_with_method(skip: MethodSkipFn) -> Self {
+        Self {
+            skip: Arc::new(RwLock::new(skip)),
+        }
+    }
+
+    /// Replaces the callable at runtime, e.g. from an admin
+    /// endpoint to silence a noisy path during an incident.
+    ///
+    /// Every clone of this [PathSkipper], including the one
+    /// held by an already built [HttpMetricsLayer], observes
+    /// the new callable from the next request on.
+    pub fn replace_fn(&self, skip: Arc<dyn Fn(&str) -> bool + 'static + Send + Sync>) {
+        self.replace_fn_with_method(Arc::new(move |_: &http::Method, path: &str| skip(path)));
+    }
+
+    /// Method-aware variant of [PathSkipper::replace_fn].
+    pub fn replace_fn_with_method(&self, skip: MethodSkipFn) {
+        *self.skip.write().unwrap_or_else(PoisonError::into_inner) = skip;
+    }
+
+    /// whether the request is skipped, the lock is released before calling the callable
+    fn skips(&self, method: &http::Method, path: &str) -> bool {
+        let skip = self.skip.read().unwrap_or_else(PoisonError::into_inner).clone();
+        skip(method, path)
+    }
+
+    /// Returns a [PathSkipper] that skips recording metrics
+    /// for requests whose path matches any of the regular
+    /// expression `patterns`.
+    ///
+    /// The patterns are compiled into a single [regex::RegexSet],
+    /// so matching stays fast even with many patterns.  Note that
+    /// the patterns are not anchored, use `^` and `$` to match
+    /// the whole path.
+    ///
+    /// Returns an error if any of the patterns is invalid.
+    ///
+    /// Requires the `regex` feature.
+    #[cfg(feature = "regex")]
+    pub fn from_patterns(patterns: &[&str]) -> Result<Self, regex::Error> {
+        let set = regex::RegexSet::new(patterns)?;
+        Ok(Self::new_with_fn(Arc::new(move |path: &str| set.is_match(path))))
+    }
+
+    /// Returns a [PathSkipper] that *only* records metrics
+    /// for requests whose path, when passed to `record`,
+    /// returns `true`, and skips everything else.
+    ///
+    /// The paths skipped by [PathSkipper::default] (`/metrics`
+    /// and `/favicon.ico`) are still skipped even if `record`
+    /// returns `true` for them.
+    pub fn allow_only(record: fn(&str) -> bool) -> Self {
+        Self::new_with_fn(Arc::new(move |path: &str| !record(path) || default_skip(path)))
+    }
+
+    /// Returns a [PathSkipper] that skips nothing, unlike
+    /// [PathSkipper::default] which skips `/metrics` and
+    /// `/favicon.ico`.
+    pub fn none() -> Self {
+        Self::new(|_| false)
+    }
+}
+
+/// the paths skipped by [PathSkipper::default]
+fn default_skip(path: &str) -> bool {
+    path.starts_with(METRICS_PATH) || path.starts_with("/favicon.ico")
+}
+
+/// A helper that instructs the metrics layer to ignore
+/// certain requests, e.g. health-check probes identified by a header.
+///
+/// Unlike [PathSkipper], it receives the full request head,
+/// and is evaluated before any metric is recorded,
+/// so the skipped requests are not even counted as active requests.
+#[derive(Clone)]
+pub struct RequestSkipper {
+    skip: RequestSkipFn,
+}
+
+impl RequestSkipper {
+    /// Returns a [RequestSkipper] that skips recording metrics
+    /// for requests whose head, when passed to `skip`, returns `true`.
+    pub fn new(skip: RequestSkipFn) -> Self {
+        Self { skip }
+    }
+}
+
+impl fmt::Debug for RequestSkipper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<skipper fn>")
+    }
+}
+
+impl fmt::Debug for PathSkipper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<skipper fn>")
+    }
+}
+
+impl Default for PathSkipper {
+    /// Returns a `PathSkipper` that skips any path which
+    /// starts with `/metrics` or `/favicon.ico``.
+    ///
+    /// This is the default implementation used when
+    /// building an HttpMetricsLayerBuilder from scratch.
+    fn default() -> Self {
+        Self::new(default_skip)
+    }
+}
+
+/// the default `http.route` value for requests without a matched route
+const DEFAULT_UNMATCHED_ROUTE_LABEL: &str = "__unmatched__";
+
+/// the default `server.address` value for requests without a `Host` header
+const DEFAULT_SERVER_ADDRESS_FALLBACK: &str = "unknown";
+
+/// the path of the metrics endpoint, see [HttpMetricsLayer::routes]
+const METRICS_PATH: &str = "/metrics";
+
+#[derive(Clone)]
+pub struct HttpMetricsLayerBuilder {
+    skipper: PathSkipper,
+    is_tls: bool,
+    unmatched_route_label: String,
+    attributes_from_request: Option<RequestAttributesFn>,
+    attributes_from_response: Option<ResponseAttributesFn>,
+    status_code_attribute: bool,
+    status_class_attribute: bool,
+    exact_response_size: bool,
+    exact_request_size: bool,
+    route_duration_buckets: HashMap<String, Vec<f64>>,
+    server_port_attribute: bool,
+    protocol_version_attribute: bool,
+    user_agent_classifier: Option<UserAgentClassifierFn>,
+    #[cfg(feature = "prometheus")]
+    registry: Option<prometheus::Registry>,
+    metric_prefix: Option<String>,
+    duration_unit: DurationUnit,
+    request_counter: bool,
+    error_counter: Option<ErrorKind>,
+    duration_metric: bool,
+    request_size_metric: bool,
+    response_size_metric: bool,
+    active_requests_metric: bool,
+    client_address: Option<ClientAddrSource>,
+    client_address_normalizer: Option<ClientAddressNormalizerFn>,
+    meter_scope: Option<(String, Option<String>)>,
+    readers: Vec<Arc<dyn MetricReader>>,
+    temporality: Option<Temporality>,
+    provider: Option<SdkMeterProvider>,
+    resource_attributes: Vec<KeyValue>,
+    self_observe: bool,
+    ttfb: bool,
+    server_address_attribute: bool,
+    server_address_normalizer: Option<ServerAddressNormalizerFn>,
+    route_normalizer: Option<RouteNormalizerFn>,
+    server_address_fallback: String,
+    trust_forwarded_headers: bool,
+    route_mode: RouteMode,
+    include_query_string: bool,
+    request_content_type_attribute: bool,
+    response_content_type_attribute: bool,
+    content_type_allow_list: Option<Vec<String>>,
+    grpc_status_attribute: bool,
+    duration_buckets: Option<Vec<f64>>,
+    size_buckets: Option<Vec<f64>>,
+    request_size_buckets: Option<Vec<f64>>,
+    response_size_buckets: Option<Vec<f64>>,
+    #[cfg(feature = "prometheus")]
+    prometheus_exporter: bool,
+    client_port_attribute: bool,
+    sampling_ratio: f64,
+    method_normalization: bool,
+    header_size_metric: bool,
+    request_skipper: Option<RequestSkipper>,
+    active_requests_mode: ActiveRequestsMode,
+    active_requests_route_label: bool,
+    constant_labels: Vec<KeyValue>,
+    concurrency_limit: Option<usize>,
+    #[cfg(feature = "prometheus")]
+    metrics_format: MetricsFormat,
+    slos: HashMap<String, SloObjective>,
+    connection_reused_attribute: bool,
+    attribute_filter: Option<AttributeFilterFn>,
+    websocket_mode: WsMode,
+    instrument_metadata: InstrumentMetadata,
+    resend_count_header: Option<http::HeaderName>,
+    sse_mode: SseMode,
+    cpu_time_metric: bool,
+    classifier: Option<Arc<dyn DynMakeClassifier>>,
+    error_type_attribute: bool,
+    detailed_attributes_on_error_only: bool,
+    phase_breakdown: bool,
+    scheme_attribute: bool,
+    meter: Option<Meter>,
+    separate_unmatched_instrument: bool,
+    original_route_attribute: bool,
+    active_requests_attributes: ActiveRequestsAttributes,
+    max_recorded_duration: Option<Duration>,
+    size_metrics_on_success_only: bool,
+    route_attributes: HashMap<String, Vec<KeyValue>>,
+    poll_ready_metric: bool,
+    baggage_attributes: Vec<String>,
+    metric_handles_extension: bool,
+}
+
+/// prints `<fn>` for a set callback, which cannot be printed itself
+fn debug_fn<T>(f: &Option<T>) -> Option<&'static str> {
+    f.as_ref().map(|_| "<fn>")
+}
+
+impl fmt::Debug for HttpMetricsLayerBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut f = f.debug_struct("HttpMetricsLayerBuilder");
+        f.field("skipper", &self.skipper)
+            .field("request_skipper", &self.request_skipper)
+            .field("is_tls", &self.is_tls)
+            .field("unmatched_route_label", &self.unmatched_route_label)
+            .field("attributes_from_request", &debug_fn(&self.attributes_from_request))
+            .field("attributes_from_response", &debug_fn(&self.attributes_from_response))
+            .field("status_code_attribute", &self.status_code_attribute)
+            .field("status_class_attribute", &self.status_class_attribute)
+            .field("exact_response_size", &self.exact_response_size)
+            .field("exact_request_size", &self.exact_request_size)
+            .field("duration_buckets", &self.duration_buckets)
+            .field("size_buckets", &self.size_buckets)
+            .field("request_size_buckets", &self.request_size_buckets)
+            .field("response_size_buckets", &self.response_size_buckets)
+            .field("route_duration_buckets", &self.route_duration_buckets)
+            .field("server_port_attribute", &self.server_port_attribute)
+            .field("protocol_version_attribute", &self.protocol_version_attribute)
+            .field("user_agent_classifier", &debug_fn(&self.user_agent_classifier))
+            .field("metric_prefix", &self.metric_prefix)
+            .field("duration_unit", &self.duration_unit)
+            .field("request_counter", &self.request_counter)
+            .field("error_counter", &self.error_counter)
+            .field("duration_metric", &self.duration_metric)
+            .field("request_size_metric", &self.request_size_metric)
+            .field("response_size_metric", &self.response_size_metric)
+            .field("active_requests_metric", &self.active_requests_metric)
+            .field("active_requests_mode", &self.active_requests_mode)
+            .field("active_requests_route_label", &self.active_requests_route_label)
+            .field("client_address", &self.client_address)
+            .field("client_address_normalizer", &debug_fn(&self.client_address_normalizer))
+            .field("client_port_attribute", &self.client_port_attribute)
+            .field("meter_scope", &self.meter_scope)
+            .field("meter", &self.meter)
+            .field("separate_unmatched_instrument", &self.separate_unmatched_instrument)
+            .field("original_route_attribute", &self.original_route_attribute)
+            .field("active_requests_attributes", &self.active_requests_attributes)
+            .field("max_recorded_duration", &self.max_recorded_duration)
+            .field("size_metrics_on_success_only", &self.size_metrics_on_success_only)
+            .field("route_attributes", &self.route_attributes)
+            .field("poll_ready_metric", &self.poll_ready_metric)
+            .field("baggage_attributes", &self.baggage_attributes)
+            .field("metric_handles_extension", &self.metric_handles_extension)
+            .field("readers", &self.readers)
+            .field("temporality", &self.temporality)
+            .field("provider", &self.provider)
+            .field("resource_attributes", &self.resource_attributes)
+            .field("self_observe", &self.self_observe)
+            .field("ttfb", &self.ttfb)
+            .field("server_address_attribute", &self.server_address_attribute)
+            .field("server_address_normalizer", &debug_fn(&self.server_address_normalizer))
+            .field("server_address_fallback", &self.server_address_fallback)
+            .field("route_normalizer", &debug_fn(&self.route_normalizer))
+            .field("trust_forwarded_headers", &self.trust_forwarded_headers)
+            .field("route_mode", &self.route_mode)
+            .field("include_query_string", &self.include_query_string)
+            .field("request_content_type_attribute", &self.request_content_type_attribute)
+            .field("response_content_type_attribute", &self.response_content_type_attribute)
+            .field("content_type_allow_list", &self.content_type_allow_list)
+            .field("grpc_status_attribute", &self.grpc_status_attribute)
+            .field("sampling_ratio", &self.sampling_ratio)
+            .field("method_normalization", &self.method_normalization)
+            .field("header_size_metric", &self.header_size_metric)
+            .field("constant_labels", &self.constant_labels)
+            .field("concurrency_limit", &self.concurrency_limit)
+            .field("slos", &self.slos)
+            .field("connection_reused_attribute", &self.connection_reused_attribute)
+            .field("attribute_filter", &debug_fn(&self.attribute_filter))
+            .field("websocket_mode", &self.websocket_mode)
+            .field("sse_mode", &self.sse_mode)
+            .field("instrument_metadata", &self.instrument_metadata)
+            .field("resend_count_header", &self.resend_count_header)
+            .field("cpu_time_metric", &self.cpu_time_metric)
+            .field("classifier", &debug_fn(&self.classifier))
+            .field("error_type_attribute", &self.error_type_attribute)
+            .field("detailed_attributes_on_error_only", &self.detailed_attributes_on_error_only)
+            .field("phase_breakdown", &self.phase_breakdown)
+            .field("scheme_attribute", &self.scheme_attribute);
+        #[cfg(feature = "prometheus")]
+        f.field("registry", &self.registry)
+            .field("prometheus_exporter", &self.prometheus_exporter)
+            .field("metrics_format", &self.metrics_format);
+        f.finish()
+    }
+}
+
+impl Default for HttpMetricsLayerBuilder {
+    fn default() -> Self {
+        HttpMetricsLayerBuilder {
+            skipper: PathSkipper::default(),
+            is_tls: false,
+            unmatched_route_label: DEFAULT_UNMATCHED_ROUTE_LABEL.to_string(),
+            attributes_from_request: None,
+            attributes_from_response: None,
+            status_code_attribute: true,
+            status_class_attribute: false,
+            exact_response_size: false,
+            exact_request_size: false,
+            route_duration_buckets: HashMap::new(),
+            server_port_attribute: false,
+            protocol_version_attribute: false,
+            user_agent_classifier: None,
+            #[cfg(feature = "prometheus")]
+            registry: None,
+            metric_prefix: None,
+            duration_unit: DurationUnit::Seconds,
+            request_counter: false,
+            error_counter: None,
+            duration_metric: true,
+            request_size_metric: true,
+            response_size_metric: true,
+            active_requests_metric: true,
+            client_address: None,
+            client_address_normalizer: None,
+            meter_scope: None,
+            readers: Vec::new(),
+            temporality: None,
+            provider: None,
+            resource_attributes: Vec::new(),
+            self_observe: false,
+            ttfb: false,
+            server_address_attribute: true,
+            server_address_normalizer: None,
+            route_normalizer: None,
+            server_address_fallback: DEFAULT_SERVER_ADDRESS_FALLBACK.to_string(),
+            trust_forwarded_headers: true,
+            route_mode: RouteMode::Template,
+            include_query_string: false,
+            request_content_type_attribute: false,
+            response_content_type_attribute: false,
+            content_type_allow_list: None,
+            grpc_status_attribute: false,
+            duration_buckets: None,
+            size_buckets: None,
+            request_size_buckets: None,
+            response_size_buckets: None,
+            #[cfg(feature = "prometheus")]
+            prometheus_exporter: false,
+            client_port_attribute: false,
+            sampling_ratio: 1.0,
+            method_normalization: true,
+            header_size_metric: false,
+            request_skipper: None,
+            active_requests_mode: ActiveRequestsMode::UpDownCounter,
+            active_requests_route_label: false,
+            constant_labels: Vec::new(),
+            concurrency_limit: None,
+            #[cfg(feature = "prometheus")]
+            metrics_format: MetricsFormat::Negotiate,
+            slos: HashMap::new(),
+            connection_reused_attribute: false,
+            attribute_filter: None,
+            websocket_mode: WsMode::Skip,
+            instrument_metadata: InstrumentMetadata::default(),
+            resend_count_header: None,
+            sse_mode: SseMode::Record,
+            cpu_time_metric: false,
+            classifier: None,
+            error_type_attribute: false,
+            detailed_attributes_on_error_only: false,
+            phase_breakdown: false,
+            scheme_attribute: true,
+            meter: None,
+            separate_unmatched_instrument: false,
+            original_route_attribute: false,
+            active_requests_attributes: ActiveRequestsAttributes::MethodScheme,
+            max_recorded_duration: None,
+            size_metrics_on_success_only: false,
+            route_attributes: HashMap::new(),
+            poll_ready_metric: false,
+            baggage_attributes: Vec::new(),
+            metric_handles_extension: false,
+        }
+    }
+}
+
+impl HttpMetricsLayerBuilder {
+    pub fn new() -> Self {
+        HttpMetricsLayerBuilder::default()
+    }
+
+    /// build a layer backed by the prometheus exporter with the default settings.
+    ///
+    /// it creates a new prometheus registry, the exporter and a [SdkMeterProvider] with it,
+    /// and returns both the layer and the registry. the provider is owned by the layer and is not
+    /// set as the global meter provider, the registry is also exported by [HttpMetricsLayer::routes].
+    #[cfg(feature = "prometheus")]
+    pub fn prometheus() -> (HttpMetricsLayer, prometheus::Registry) {
+        let registry = prometheus::Registry::new();
+        let exporter = opentelemetry_prometheus::exporter()
+            .with_registry(registry.clone())
+            .build()
+            .expect("failed to register the prometheus exporter to a new registry");
+        let provider = SdkMeterProvider::builder().with_reader(exporter).build();
+
+        let mut layer = HttpMetricsLayerBuilder::new()
+            .with_prometheus_registry(registry.clone())
+            .build_with_provider(&provider);
+        layer.provider = Some(provider);
+        (layer, registry)
+    }
+
+    /// set the [PathSkipper] deciding which requests are not recorded.
+    ///
+    /// the default one skips the paths starting with `/metrics` or `/favicon.ico`,
+    /// use [PathSkipper::none] to record every request, e.g. when `/metrics` is a business route.
+    pub fn with_skipper(mut self, skipper: PathSkipper) -> Self {
+        self.skipper = skipper;
+        self
+    }
+
+    /// set a [RequestSkipper] to skip requests by their head, e.g. by a header,
+    /// it is evaluated in addition to the [PathSkipper].
+    pub fn with_request_skipper(mut self, skipper: RequestSkipper) -> Self {
+        self.request_skipper = Some(skipper);
+        self
+    }
+
+    /// set whether the service is running as a TLS server.
+    ///
+    /// when set to `true`, the `url.scheme` attribute is always `https`,
+    /// the `X-Forwarded-*` headers will not be consulted.
+    /// the [TlsConnection] request extension still takes precedence.
+    pub fn with_is_tls(mut self, is_tls: bool) -> Self {
+        self.is_tls = is_tls;
+        self
+    }
+
+    /// set whether to trust the `X-Forwarded-Proto`, `X-Forwarded-Protocol`, `X-Forwarded-Ssl`
+    /// and `X-Url-Scheme` headers to determine the `url.scheme` attribute, default to `true`.
+    ///
+    /// it is only safe to trust them behind a proxy which overwrites (or strips) these headers
+    /// sent by the clients, otherwise the clients can spoof the scheme.
+    /// when set to `false`, the scheme is decided by [TlsConnection] and [HttpMetricsLayerBuilder::with_is_tls] only,
+    /// which is the safe choice for services exposed directly, or behind a proxy passing the headers through.
+    pub fn with_trust_forwarded_headers(mut self, trust: bool) -> Self {
+        self.trust_forwarded_headers = trust;
+        self
+    }
+
+    /// set the `http.route` value recorded for requests without a matched route
+    /// (e.g. 404 or requests handled by the fallback), default to `__unmatched__`.
+    ///
+    /// the label is also passed to the [PathSkipper] as the path of such requests.
+    pub fn with_unmatched_route_label(mut self, label: String) -> Self {
+        self.unmatched_route_label = label;
+        self
+    }
+
+    /// set a callback to extract extra attributes from the request parts,
+    /// the returned attributes are appended to the attributes of
+    /// `http.server.request.duration`, `http.server.request.size` and `http.server.response.size`.
+    ///
+    /// be careful about the cardinality: every distinct attribute value creates a new time series,
+    /// so never return unbounded values such as user ids or raw paths.
+    pub fn with_attributes_from_request(mut self, f: RequestAttributesFn) -> Self {
+        self.attributes_from_request = Some(f);
+        self
+    }
+
+    /// set a callback to extract extra attributes from the response parts, e.g. from a `X-Cache` header,
+    /// the returned attributes are appended like the ones of [HttpMetricsLayerBuilder::with_attributes_from_request].
+    ///
+    /// the same cardinality caveats apply.
+    pub fn with_attributes_from_response(mut self, f: ResponseAttributesFn) -> Self {
+        self.attributes_from_response = Some(f);
+        self
+    }
+
+    /// set whether to record the `http.response.status_code` attribute, default to `true`.
+    ///
+    /// disable it together with [HttpMetricsLayerBuilder::with_status_class_attribute]
+    /// to only keep the status class and reduce the cardinality.
+    pub fn with_status_code_attribute(mut self, enabled: bool) -> Self {
+        self.status_code_attribute = enabled;
+        self
+    }
+
+    /// set whether to record the `http.response.status_class` attribute, default to `false`.
+    ///
+    /// the value is one of `1xx`, `2xx`, `3xx`, `4xx` and `5xx`.
+    pub fn with_status_class_attribute(mut self, enabled: bool) -> Self {
+        self.status_class_attribute = enabled;
+        self
+    }
+
+    /// set whether to count the bytes actually sent in the response body, default to `false`.
+    ///
+    /// by default the response size is taken from the body's size hint,
+    /// which is `0` for streaming bodies without a known length.
+    /// when enabled, the response body is wrapped to count the bytes as they are polled,
+    /// and `http.server.response.size` is recorded once the body is finished (or dropped).
+    /// this adds a little overhead on every body chunk.
+    pub fn with_exact_response_size(mut self, enabled: bool) -> Self {
+        self.exact_response_size = enabled;
+        self
+    }
+
+    /// set whether to count the bytes actually received in the request body, default to `false`.
+    ///
+    /// by default the request body size is taken from the `Content-Length` header,
+    /// which is missing for chunked uploads.
+    /// when enabled, the request body is wrapped to count the bytes as they are read by the handler,
+    /// and `http.server.request.size` is recorded once both the response is ready
+    /// and the request body is finished (or dropped).
+    pub fn with_exact_request_size(mut self, enabled: bool) -> Self {
+        self.exact_request_size = enabled;
+        self
+    }
+
+    /// set custom duration buckets for the given route, e.g. a slow `/upload` route.
+    ///
+    /// a histogram can only have one set of buckets, so the route gets its own instrument,
+    /// named `http.server.request.duration.` followed by the route with every character
+    /// other than ASCII letters and digits replaced by `_`, and leading / trailing `_` trimmed.
+    /// e.g. `/upload` is recorded into `http.server.request.duration.upload`,
+    /// `/files/{id}` is recorded into `http.server.request.duration.files__id`.
+    ///
+    /// the route must be the matched route, as recorded in the `http.route` attribute.
+    pub fn with_route_duration_buckets(mut self, route: &str, buckets: Vec<f64>) -> Self {
+        self.route_duration_buckets.insert(route.to_string(), buckets);
+        self
+    }
+
+    /// track a service level objective of the route: the requests answered within `latency_threshold`
+    /// with a status in `good_status_classes` are counted in the `http.server.slo.good` counter,
+    /// the others in the `http.server.slo.bad` counter, with the `http.request.method` and route attributes.
+    ///
+    /// like the duration histogram, the latency is measured until the response headers are sent.
+    /// the route must be the matched route, as recorded in the `http.route` attribute,
+    /// setting the objective of a route again replaces it.
+    pub fn with_slo(mut self, route: &str, latency_threshold: Duration, good_status_classes: Vec<StatusClass>) -> Self {
+        self.slos.insert(
+            route.to_string(),
+            SloObjective {
+                latency_threshold,
+                good_status_classes,
+            },
+        );
+        self
+    }
+
+    /// set which attribute carries the route, default to [RouteMode::Template].
+    ///
+    /// note that the raw path of [RouteMode::RawPath] and [RouteMode::Both] is high-cardinality,
+    /// every distinct path (e.g. each user id) creates a new time series.
+    pub fn with_route_attribute_mode(mut self, mode: RouteMode) -> Self {
+        self.route_mode = mode;
+        self
+    }
+
+    /// set whether the `http.target` attribute of [RouteMode::RawPath] and [RouteMode::Both]
+    /// includes the query string, default to `false`.
+    ///
+    /// by default everything from the first `?` is stripped, the query string explodes the cardinality,
+    /// and may carry secrets which should not be kept in the metrics store.
+    pub fn with_include_query_string(mut self, enabled: bool) -> Self {
+        self.include_query_string = enabled;
+        self
+    }
+
+    /// set the buckets of the request duration histogram, in the unit set by
+    /// [HttpMetricsLayerBuilder::with_duration_unit].
+    ///
+    /// default to the buckets recommended by the semantic conventions,
+    /// `[0, 0.005, 0.01, 0.025, 0.05, 0.075, 0.1, 0.25, 0.5, 0.75, 1, 2.5, 5, 7.5, 10]` seconds.
+    /// invalid buckets are rejected by [HttpMetricsLayerBuilder::try_build], and replaced by the default ones by
+    /// [HttpMetricsLayerBuilder::build].
+    pub fn with_duration_buckets(mut self, buckets: Vec<f64>) -> Self {
+        self.duration_buckets = Some(buckets);
+        self
+    }
+
+    /// set the buckets of the request and response size histograms, in bytes.
+    ///
+    /// invalid buckets are handled the same as [HttpMetricsLayerBuilder::with_duration_buckets].
+    /// the buckets set by [HttpMetricsLayerBuilder::with_request_size_buckets] or
+    /// [HttpMetricsLayerBuilder::with_response_size_buckets] take precedence.
+    pub fn with_size_buckets(mut self, buckets: Vec<f64>) -> Self {
+        self.size_buckets = Some(buckets);
+        self
+    }
+
+    /// set the buckets of the request size histogram only, in bytes.
+    pub fn with_request_size_buckets(mut self, buckets: Vec<f64>) -> Self {
+        self.request_size_buckets = Some(buckets);
+        self
+    }
+
+    /// set the buckets of the response size histogram only, in bytes.
+    pub fn with_response_size_buckets(mut self, buckets: Vec<f64>) -> Self {
+        self.response_size_buckets = Some(buckets);
+        self
+    }
+
+    /// set a callback to rewrite the matched route, e.g. to merge `/v1/...` and `/v2/...` routes.
+    ///
+    /// the rewritten route is used for the [PathSkipper], the `http.route` attribute,
+    /// and to look up the buckets set by [HttpMetricsLayerBuilder::with_route_duration_buckets].
+    pub fn with_route_normalizer(mut self, normalizer: RouteNormalizerFn) -> Self {
+        self.route_normalizer = Some(normalizer);
+        self
+    }
+
+    /// record only a fraction of the requests, the ratio is clamped to `0.0..=1.0`, default to `1.0`.
+    ///
+    /// the requests are sampled with a fast thread-local random number in the middleware.
+    /// the active requests (and [HttpMetricsLayer::active_requests]) are always exact,
+    /// all the other histograms and counters only record the sampled requests,
+    /// so the counts must be scaled by the inverse of the ratio.
+    pub fn with_sampling_ratio(mut self, ratio: f64) -> Self {
+        self.sampling_ratio = if ratio.is_nan() { 1.0 } else { ratio.clamp(0.0, 1.0) };
+        self
+    }
+
+    /// set whether to record the non-standard methods as `_OTHER` in the `http.request.method` attribute,
+    /// as required by the semantic conventions to bound the cardinality. default to `true`.
+    ///
+    /// the standard methods are `GET`, `HEAD`, `POST`, `PUT`, `DELETE`, `CONNECT`, `OPTIONS`, `TRACE` and `PATCH`,
+    /// set to `false` to record the raw method, e.g. for the `PURGE` method of a cache server.
+    pub fn with_method_normalization(mut self, enabled: bool) -> Self {
+        self.method_normalization = enabled;
+        self
+    }
+
+    /// set whether to record the `http.server.request.header.size` histogram, default to `false`.
+    ///
+    /// the size is the sum of the lengths of the request header names and values,
+    /// e.g. to alert when clients start sending oversized header sets.
+    pub fn with_header_size_metric(mut self, enabled: bool) -> Self {
+        self.header_size_metric = enabled;
+        self
+    }
+
+    /// set how the `http.server.active_requests` metric is recorded, default to [ActiveRequestsMode::UpDownCounter].
+    ///
+    /// with delta temporality, the `UpDownCounter` exports the change of each interval (e.g. `+1` then `-1`),
+    /// which the backend must sum up, some push backends mishandle it.
+    /// the [ActiveRequestsMode::Gauge] mode always exports the current number of the in-flight requests,
+    /// whatever the temporality is, at the cost of the `http.request.method` and `url.scheme` attributes.
+    pub fn with_active_requests_mode(mut self, mode: ActiveRequestsMode) -> Self {
+        self.active_requests_mode = mode;
+        self
+    }
+
+    /// override the descriptions and units of the instruments, e.g. to localize the descriptions.
+    pub fn with_instrument_metadata(mut self, metadata: InstrumentMetadata) -> Self {
+        self.instrument_metadata = metadata;
+        self
+    }
+
+    /// set how the upgraded (`101 Switching Protocols`) responses are recorded, default to [WsMode::Skip].
+    ///
+    /// the upgraded connections, e.g. WebSocket, live on long after the response,
+    /// their request durations and body sizes would pollute the histograms of the regular requests.
+    pub fn with_websocket_handling(mut self, mode: WsMode) -> Self {
+        self.websocket_mode = mode;
+        self
+    }
+
+    /// set how the server-sent events responses are recorded, default to [SseMode::Record].
+    ///
+    /// the event streams stay open for long, their time to the response headers says little,
+    /// and the time to the end of the stream would pollute the histograms of the regular requests.
+    pub fn with_sse_handling(mut self, mode: SseMode) -> Self {
+        self.sse_mode = mode;
+        self
+    }
+
+    /// set whether to record the `http.server.request.cpu_time` histogram, default to `false`.
+    /// requires the `cpu-time` feature, and is only supported on unix.
+    ///
+    /// it is the thread CPU time accumulated while polling the response future, in the duration unit,
+    /// an approximation of the CPU time of the request: the work of the spawned tasks and the blocking
+    /// thread pool is not counted, neither is the work of other tasks if the inner future blocks the thread.
+    #[cfg(feature = "cpu-time")]
+    pub fn with_cpu_time_metric(mut self, enabled: bool) -> Self {
+        self.cpu_time_metric = enabled;
+        self
+    }
+
+    /// set whether to record the `server.port` attribute, default to `false`.
+    ///
+    /// the port is parsed from the `Host` header, if the header has no port, it falls back to `443`
+    /// when the request scheme is `https` or `80` otherwise. the scheme is resolved the same way as
+    /// the `url.scheme` attribute: [TlsConnection], then [HttpMetricsLayerBuilder::with_is_tls],
+    /// then the `X-Forwarded-Proto` headers when they are trusted.
+    pub fn with_server_port_attribute(mut self, enabled: bool) -> Self {
+        self.server_port_attribute = enabled;
+        self
+    }
+
+    /// set whether to record the `server.address` attribute, default to `true`.
+    ///
+    /// the `Host` header may take many distinct values, e.g. on a multi-tenant vhost setup,
+    /// disable it or normalize it with [HttpMetricsLayerBuilder::with_server_address_normalizer]
+    /// to control the cardinality.
+    pub fn with_server_address_attribute(mut self, enabled: bool) -> Self {
+        self.server_address_attribute = enabled;
+        self
+    }
+
+    /// set the `server.address` value recorded for requests without a valid `Host` header, default to `unknown`,
+    /// e.g. the canonical hostname behind a load balancer stripping the header.
+    /// an empty fallback omits the attribute from those requests.
+    ///
+    /// otherwise the value is the host identifier of the request target or the `Host` header,
+    /// without the port and the brackets of IPv6 addresses.
+    pub fn with_server_address_fallback(mut self, fallback: String) -> Self {
+        self.server_address_fallback = fallback;
+        self
+    }
+
+    /// set a callback to normalize the `server.address` attribute, e.g. to map the hosts to a known set.
+    pub fn with_server_address_normalizer(mut self, normalizer: ServerAddressNormalizerFn) -> Self {
+        self.server_address_normalizer = Some(normalizer);
+        self
+    }
+
+    /// set whether to record the `http.request.content_type` attribute, default to `false`.
+    ///
+    /// only the media type of the `Content-Type` header is kept, e.g. `application/json`
+    /// for `application/json; charset=utf-8`, the attribute is omitted if the header is absent.
+    /// see [HttpMetricsLayerBuilder::with_content_type_allow_list] to bound the cardinality.
+    pub fn with_request_content_type_attribute(mut self, enabled: bool) -> Self {
+        self.request_content_type_attribute = enabled;
+        self
+    }
+
+    /// set whether to record the `http.response.content_type` attribute, default to `false`.
+    ///
+    /// same as [HttpMetricsLayerBuilder::with_request_content_type_attribute], for the response.
+    pub fn with_response_content_type_attribute(mut self, enabled: bool) -> Self {
+        self.response_content_type_attribute = enabled;
+        self
+    }
+
+    /// set the media types recorded as is in the content type attributes,
+    /// other media types are recorded as `other`. default to record all the media types.
+    pub fn with_content_type_allow_list(mut self, content_types: Vec<String>) -> Self {
+        self.content_type_allow_list = Some(content_types.into_iter().map(|c| c.to_ascii_lowercase()).collect());
+        self
+    }
+
+    /// set whether to record the `rpc.grpc.status_code` attribute from the `grpc-status` header
+    /// or trailer, default to `false`.
+    ///
+    /// the attribute is omitted for plain HTTP responses without the `grpc-status` header.
+    /// for gRPC responses with the status in the trailers, the metrics (except the time to first byte)
+    /// are recorded once the trailers are received, with the latency measured when the response is ready.
+    pub fn with_grpc_status_attribute(mut self, enabled: bool) -> Self {
+        self.grpc_status_attribute = enabled;
+        self
+    }
+
+    /// set whether to record the `network.protocol.version` attribute, default to `false`.
+    ///
+    /// the value is the HTTP version of the request, e.g. `1.1`, `2`.
+    pub fn with_protocol_version_attribute(mut self, enabled: bool) -> Self {
+        self.protocol_version_attribute = enabled;
+        self
+    }
+
+    /// set a classifier to record the `user_agent.category` attribute.
+    ///
+    /// the classifier receives the `User-Agent` header value (empty string if absent),
+    /// and should return a low-cardinality category, e.g. `bot`, `browser` or `other`.
+    /// never return the raw user agent, which has a huge cardinality.
+    pub fn with_user_agent_classifier(mut self, classifier: UserAgentClassifierFn) -> Self {
+        self.user_agent_classifier = Some(classifier);
+        self
+    }
+
+    /// set the exposition format of [HttpMetricsLayer::routes], default to [MetricsFormat::Negotiate],
+    /// which serves the protobuf format to the prometheus servers configured to scrape it.
+    #[cfg(feature = "prometheus")]
+    pub fn with_metrics_format(mut self, format: MetricsFormat) -> Self {
+        self.metrics_format = format;
+        self
+    }
+
+    /// set the prometheus registry exported by [HttpMetricsLayer::routes],
+    /// it should be the registry used by the prometheus exporter of the meter provider.
+    #[cfg(feature = "prometheus")]
+    pub fn with_prometheus_registry(mut self, registry: prometheus::Registry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// set a prefix for the names of all the instruments, separated by a `.`,
+    /// e.g. with prefix `myapp`, the request duration is recorded into `myapp.http.server.request.duration`,
+    /// which is exported as `myapp_http_server_request_duration_seconds` by the prometheus exporter.
+    ///
+    /// by default there is no prefix, the names follow the semantic conventions.
+    pub fn with_metric_prefix(mut self, prefix: String) -> Self {
+        self.metric_prefix = Some(prefix);
+        self
+    }
+
+    /// set the unit of the request duration, default to [DurationUnit::Seconds] as the semantic conventions.
+    ///
+    /// the default buckets are scaled to the unit, while custom buckets
+    /// (e.g. [HttpMetricsLayerBuilder::with_route_duration_buckets]) are interpreted in the chosen unit as is.
+    pub fn with_duration_unit(mut self, unit: DurationUnit) -> Self {
+        self.duration_unit = unit;
+        self
+    }
+
+    /// set whether to record the `http.server.request.count` counter, default to `false`.
+    ///
+    /// the counter has the same attributes as the histograms and is incremented once per recorded request.
+    pub fn with_request_counter(mut self, enabled: bool) -> Self {
+        self.request_counter = enabled;
+        self
+    }
+
+    /// enable the `http.server.request.errors` counter, which counts the error responses of the given kind.
+    ///
+    /// the counter has the `http.request.method`, `http.route` and `http.response.status_code` attributes.
+    pub fn with_error_counter(mut self, kind: ErrorKind) -> Self {
+        self.error_counter = Some(kind);
+        self
+    }
+
+    /// set whether to record the `http.server.request.duration` histogram, default to `true`.
+    pub fn with_duration(mut self, enabled: bool) -> Self {
+        self.duration_metric = enabled;
+        self
+    }
+
+    /// set whether to record the `http.server.request.size` histogram, default to `true`.
+    pub fn with_request_size(mut self, enabled: bool) -> Self {
+        self.request_size_metric = enabled;
+        self
+    }
+
+    /// set whether to record the `http.server.response.size` histogram, default to `true`.
+    pub fn with_response_size(mut self, enabled: bool) -> Self {
+        self.response_size_metric = enabled;
+        self
+    }
+
+    /// set whether to record the `http.server.active_requests` counter, default to `true`.
+    pub fn with_active_requests(mut self, enabled: bool) -> Self {
+        self.active_requests_metric = enabled;
+        self
+    }
+
+    /// record the `http.server.concurrency.utilization` gauge, the active requests divided by `limit`,
+    /// e.g. the limit of a `tower::limit::ConcurrencyLimitLayer`. a zero limit is ignored.
+    ///
+    /// the requests skipped by the middleware are not counted.
+    pub fn with_concurrency_limit(mut self, limit: usize) -> Self {
+        self.concurrency_limit = Some(limit);
+        self
+    }
+
+    /// set whether to add the `http.route` attribute to the `http.server.active_requests` counter, default to `false`.
+    ///
+    /// only the requests matching a route get the attribute, it raises the cardinality of the counter.
+    pub fn with_active_requests_route_label(mut self, enabled: bool) -> Self {
+        self.active_requests_route_label = enabled;
+        self
+    }
+
+    /// record the `client.address` attribute, resolved from the given source.
+    ///
+    /// the attribute is omitted if the source is not available for a request.
+    /// raw IP addresses have a huge cardinality,
+    /// consider normalizing them with [HttpMetricsLayerBuilder::with_client_address_normalizer].
+    pub fn with_client_address(mut self, source: ClientAddrSource) -> Self {
+        self.client_address = Some(source);
+        self
+    }
+
+    /// set whether to record the `http.connection.reused` attribute, default to `false`.
+    ///
+    /// the attribute is read from the [ConnectionReused] request extension, it is omitted without it.
+    pub fn with_connection_reused_attribute(mut self, enabled: bool) -> Self {
+        self.connection_reused_attribute = enabled;
+        self
+    }
+
+    /// set a filter to drop attributes per instrument, e.g. to strip `server.address` from the size histograms only.
+    ///
+    /// the filter is called with the instrument name without the prefix of
+    /// [HttpMetricsLayerBuilder::with_metric_prefix], e.g. `http.server.request.duration`
+    /// (also for the histograms of [HttpMetricsLayerBuilder::with_route_duration_buckets]),
+    /// and each attribute of a recording, it returns `false` to drop the attribute.
+    /// it runs for every recording, so keep it cheap.
+    ///
+    /// the `http.server.active_requests` attributes are filtered once when the request starts,
+    /// so the counter still balances to zero.
+    pub fn with_attribute_filter(mut self, filter: AttributeFilterFn) -> Self {
+        self.attribute_filter = Some(filter);
+        self
+    }
+
+    /// record the `http.request.resend_count` attribute, read from the given request header, e.g. `X-Retry-Count`.
+    ///
+    /// to bound the cardinality, the count is bucketed into `0`, `1`, `2` and `3+`,
+    /// a missing or invalid header counts as `0`.
+    pub fn with_resend_count_header(mut self, header: http::HeaderName) -> Self {
+        self.resend_count_header = Some(header);
+        self
+    }
+
+    /// set whether to record the `client.port` attribute, default to `false`.
+    ///
+    /// the port is only known for [ClientAddrSource::ConnectInfo], it is omitted for the other sources,
+    /// or if the app is not served with [axum::Router::into_make_service_with_connect_info].
+    /// the client ports are ephemeral, this is mostly useful to correlate with connection-level logs.
+    pub fn with_client_port_attribute(mut self, enabled: bool) -> Self {
+        self.client_port_attribute = enabled;
+        self
+    }
+
+    /// set a callback to normalize the `client.address` attribute, e.g. to bucket IPv4 addresses by /24.
+    pub fn with_client_address_normalizer(mut self, normalizer: ClientAddressNormalizerFn) -> Self {
+        self.client_address_normalizer = Some(normalizer);
+        self
+    }
+
+    /// set the instrumentation scope name and version of the meter,
+    /// default to the name and version of this crate.
+    pub fn with_meter_scope(mut self, name: impl Into<String>, version: Option<String>) -> Self {
+        self.meter_scope = Some((name.into(), version));
+        self
+    }
+
+    /// create the instruments from the given meter, e.g. one shared by an observability module
+    /// which manages the instrumentation scope and the resource centrally.
+    ///
+    /// it takes precedence over [HttpMetricsLayerBuilder::with_provider], the metric readers
+    /// and [HttpMetricsLayerBuilder::with_meter_scope], which are ignored with a warning.
+    pub fn with_meter(mut self, meter: Meter) -> Self {
+        self.meter = Some(meter);
+        self
+    }
+
+    /// use the given meter provider instead of the global meter provider.
+    pub fn with_provider(mut self, provider: SdkMeterProvider) -> Self {
+        self.provider = Some(provider);
+        self
+    }
+
+    /// add a metric reader to the meter provider created by the builder.
+    ///
+    /// when at least one reader is set, [HttpMetricsLayerBuilder::build] creates its own
+    /// [SdkMeterProvider] with the readers, instead of using the global meter provider.
+    /// the readers are ignored if [HttpMetricsLayerBuilder::with_provider] is set.
+    pub fn with_metric_reader(mut self, reader: impl MetricReader) -> Self {
+        self.readers.push(Arc::new(reader));
+        self
+    }
+
+    /// export the metrics both to prometheus and to another reader, e.g. an OTLP periodic reader.
+    ///
+    /// the builder creates a prometheus exporter with the registry, which is exported by [HttpMetricsLayer::routes],
+    /// and a [SdkMeterProvider] carrying both the exporter and the reader (and any other reader set by
+    /// [HttpMetricsLayerBuilder::with_metric_reader]). the instruments are recorded once and fan out to all the readers.
+    #[cfg(feature = "prometheus")]
+    pub fn with_dual_export(mut self, registry: prometheus::Registry, reader: impl MetricReader) -> Self {
+        self.registry = Some(registry);
+        self.prometheus_exporter = true;
+        self.with_metric_reader(reader)
+    }
+
+    /// set the temporality of the readers of the meter provider created by the builder,
+    /// e.g. [Temporality::Delta] for push based backends which can not handle cumulative resets.
+    ///
+    /// this only applies to the readers set by [HttpMetricsLayerBuilder::with_metric_reader],
+    /// it is a no-op when the global meter provider or [HttpMetricsLayerBuilder::with_provider] is used,
+    /// since the temporality is then decided by the readers (or exporters) that provider was built with.
+    /// default to the temporality of each reader, which is cumulative for the prometheus exporter.
+    pub fn with_temporality(mut self, temporality: Temporality) -> Self {
+        self.temporality = Some(temporality);
+        self
+    }
+
+    /// merge extra resource attributes, e.g. `service.name` or `deployment.environment`,
+    /// into the default resource of the meter provider created by the builder.
+    ///
+    /// like [HttpMetricsLayerBuilder::with_temporality], this only applies to the readers set by
+    /// [HttpMetricsLayerBuilder::with_metric_reader], otherwise it is ignored with a debug log.
+    pub fn with_resource_attributes(mut self, attributes: Vec<KeyValue>) -> Self {
+        self.resource_attributes.extend(attributes);
+        self
+    }
+
+    /// add a fixed set of attributes, e.g. `env=dev`, to every metric recorded by the middleware,
+    /// including the `http.server.active_requests` counter.
+    ///
+    /// unlike [HttpMetricsLayerBuilder::with_resource_attributes], they are exported as labels of every
+    /// prometheus series, to set them on the registry instead, see `prometheus::Registry::new_custom`.
+    pub fn with_constant_labels(mut self, labels: Vec<KeyValue>) -> Self {
+        self.constant_labels.extend(labels);
+        self
+    }
+
+    /// record the duration of the metrics endpoint scrapes into the `http.server.scrape.duration` histogram,
+    /// instead of skipping them entirely. the main histograms are not affected. default to `false`.
+    pub fn with_self_observe(mut self, enabled: bool) -> Self {
+        self.self_observe = enabled;
+        self
+    }
+
+    /// record the elapsed time until the first chunk of the response body is sent
+    /// into the `http.server.response.time_to_first_byte` histogram, default to `false`.
+    ///
+    /// this is useful for streaming endpoints, where the total duration conflates
+    /// the time to produce the response with the time spent streaming it.
+    pub fn with_ttfb(mut self, enabled: bool) -> Self {
+        self.ttfb = enabled;
+        self
+    }
+
+    /// build the layer, fail on invalid configuration, e.g. empty or unsorted histogram buckets,
+    /// or if the prometheus exporter of `HttpMetricsLayerBuilder::with_dual_export` failed to register.
+    pub fn try_build(mut self) -> Result<HttpMetricsLayer, HttpMetricsError> {
+        let mut buckets = self
+            .duration_buckets
+            .iter()
+            .chain(&self.size_buckets)
+            .chain(&self.request_size_buckets)
+            .chain(&self.response_size_buckets)
+            .chain(self.route_duration_buckets.values());
+        if buckets.any(|b| !valid_buckets(b)) {
+            return Err(HttpMetricsError::InvalidBuckets);
+        }
+
+        if let Some(meter) = self.meter.take() {
+            if self.provider.is_some() || !self.readers.is_empty() || self.meter_scope.is_some() {
+                tracing::warn!("the meter set by `with_meter` takes precedence, the meter provider and scope are ignored");
+            }
+            return Ok(self.build_with_meter(meter));
+        }
+
+        #[cfg(feature = "prometheus")]
+        let prometheus_exporter = self.prometheus_exporter;
+        #[cfg(not(feature = "prometheus"))]
+        let prometheus_exporter = false;
+
+        if self.provider.is_some() || (self.readers.is_empty() && !prometheus_exporter) {
+            if !self.resource_attributes.is_empty() {
+                tracing::debug!("resource attributes are ignored, the resource of the meter provider is already fixed");
+            }
+            return Ok(match self.provider.take() {
+                Some(provider) => {
+                    let mut layer = self.build_with_provider(&provider);
+                    layer.provider = Some(provider);
+                    layer
+                }
+                None => self.build_with_provider(global::meter_provider().as_ref()),
+            });
+        }
+
+        #[cfg(feature = "prometheus")]
+        if self.prometheus_exporter {
+            let registry = self.registry.clone().unwrap_or_default();
+            let exporter = opentelemetry_prometheus::exporter()
+                .with_registry(registry)
+                .build()
+                .map_err(HttpMetricsError::Registry)?;
+            self.readers.push(Arc::new(exporter));
+        }
+
+        let resource = Resource::new_with_defaults(std::mem::take(&mut self.resource_attributes));
+        let provider = std::mem::take(&mut self.readers)
+            .into_iter()
+            .fold(SdkMeterProvider::builder().with_resource(resource), |builder, reader| {
+                builder.with_reader(LayerReader {
+                    inner: reader,
+                    temporality: self.temporality,
+                })
+            })
+            .build();
+        let mut layer = self.build_with_provider(&provider);
+        layer.provider = Some(provider);
+        Ok(layer)
+    }
+
+    /// the duration buckets set by [HttpMetricsLayerBuilder::with_duration_buckets], `None` for the default ones
+    pub fn duration_buckets(&self) -> Option<&[f64]> {
+        self.duration_buckets.as_deref()
+    }
+
+    /// the size buckets set by [HttpMetricsLayerBuilder::with_size_buckets], `None` for the default ones
+    pub fn size_buckets(&self) -> Option<&[f64]> {
+        self.size_buckets.as_deref()
+    }
+
+    /// whether a `GET` request to the path (or the matched route) would be skipped by the [PathSkipper].
+    ///
+    /// the [RequestSkipper] is not consulted, since it needs the whole request head.
+    pub fn would_skip(&self, path: &str) -> bool {
+        self.skipper.skips(&http::Method::GET, path)
+    }
+
+    /// classify the responses with a `tower_http` [MakeClassifier], e.g. the same one as a `TraceLayer`,
+    /// so "error" means the same thing to the traces and the metrics.
+    ///
+    /// a failed response gets the `error.type` attribute, the failure class formatted by [fmt::Display],
+    /// and it is counted by [HttpMetricsLayerBuilder::with_error_counter] regardless of the [ErrorKind].
+    /// the classifiers waiting for the end of the stream, e.g. `GrpcErrorsAsFailures`, are run on the trailers.
+    /// without a classifier, the errors are detected by the status code.
+    pub fn with_classifier<C>(mut self, classifier: C) -> Self
+    where
+        C: MakeClassifier + Send + Sync + 'static,
+        C::Classifier: Send + 'static,
+        C::ClassifyEos: Send + 'static,
+        C::FailureClass: fmt::Display,
+    {
+        self.classifier = Some(Arc::new(classifier));
+        self
+    }
+
+    /// set whether to record the `error.type` attribute of the failed requests, default to `false`.
+    ///
+    /// the value is the status code, e.g. `500`, for the client and server error responses,
+    /// and the type name of the error on `http.server.request.failures` when the inner service fails.
+    /// the responses classified by [HttpMetricsLayerBuilder::with_classifier] get the failure class instead.
+    pub fn with_error_type_attribute(mut self, enabled: bool) -> Self {
+        self.error_type_attribute = enabled;
+        self
+    }
+
+    /// set whether only the failed requests are recorded with the full attributes, default to `false`.
+    ///
+    /// the successful requests are recorded with `http.request.method`, `http.response.status_class`
+    /// and the constant labels only, which bounds the cardinality of a high-throughput service,
+    /// while the client and server error responses keep all the attributes, e.g. the route and `server.address`.
+    /// with [HttpMetricsLayerBuilder::with_classifier], the classifier decides which requests failed,
+    /// and the responses classified at the end of the stream keep all the attributes.
+    pub fn with_detailed_attributes_on_error_only(mut self, enabled: bool) -> Self {
+        self.detailed_attributes_on_error_only = enabled;
+        self
+    }
+
+    /// record the duration split by phase, default to `false`:
+    /// the time spent in the handler until the response headers are returned into `http.server.handler.duration`,
+    /// and the time from the first to the last chunk of the response body into `http.server.response.body.duration`.
+    ///
+    /// this tells apart a slow handler from a slow client receiving a large response.
+    pub fn with_phase_breakdown(mut self, enabled: bool) -> Self {
+        self.phase_breakdown = enabled;
+        self
+    }
+
+    /// set whether to record the `url.scheme` attribute of `http.server.active_requests`, default to `true`.
+    ///
+    /// when set to `false`, the scheme is not detected at all, which suits a plaintext-only internal service
+    /// where the attribute is always `http`.
+    pub fn with_scheme_attribute(mut self, enabled: bool) -> Self {
+        self.scheme_attribute = enabled;
+        self
+    }
+
+    /// set whether the requests without a matched route are recorded into their own instruments,
+    /// `http.server.unmatched.request.duration`, `http.server.unmatched.request.size`
+    /// and `http.server.unmatched.response.size`, default to `false`.
+    ///
+    /// they only have the `http.request.method`, `http.response.status_code` and the constant labels,
+    /// and are left out of the main instruments, so scanners and 404s never mix with the route histograms.
+    pub fn with_separate_unmatched_instrument(mut self, enabled: bool) -> Self {
+        self.separate_unmatched_instrument = enabled;
+        self
+    }
+
+    /// set whether to record the `http.route.original` attribute, default to `false`.
+    ///
+    /// the value is the path of the axum `OriginalUri`, without the query and rewritten by
+    /// [HttpMetricsLayerBuilder::with_route_normalizer], so a layer inside a nested router still sees
+    /// the full path, e.g. `/sub/sub1` rather than `/sub1`. it is the raw path, mind the cardinality.
+    pub fn with_original_route_attribute(mut self, enabled: bool) -> Self {
+        self.original_route_attribute = enabled;
+        self
+    }
+
+    /// set the attributes of the `http.server.active_requests` counter independently of the histograms,
+    /// default to [ActiveRequestsAttributes::MethodScheme].
+    ///
+    /// the scheme is only detected when it is recorded, [HttpMetricsLayerBuilder::with_scheme_attribute]
+    /// set to `false` also leaves it out.
+    pub fn with_active_requests_attributes(mut self, attributes: ActiveRequestsAttributes) -> Self {
+        self.active_requests_attributes = attributes;
+        self
+    }
+
+    /// clamp the recorded request durations to the given cap, e.g. against the absurd durations measured
+    /// across the suspension of a VM, and count the clamped ones in `http.server.duration.clamped`.
+    ///
+    /// the counter has the `http.request.method`, the route attribute and the constant labels.
+    pub fn with_max_recorded_duration(mut self, max: Duration) -> Self {
+        self.max_recorded_duration = Some(max);
+        self
+    }
+
+    /// set whether the `http.server.request.size` and `http.server.response.size` histograms
+    /// only record the successful (2xx) responses, default to `false`.
+    ///
+    /// the error bodies are usually tiny, leaving them out saves their series, the other metrics are unaffected.
+    pub fn with_size_metrics_on_success_only(mut self, enabled: bool) -> Self {
+        self.size_metrics_on_success_only = enabled;
+        self
+    }
+
+    /// add static attributes to the requests of the given routes, e.g. a `domain` attribute
+    /// mapping `/checkout/{id}` to `payments`, for rollups by team or domain.
+    ///
+    /// the keys must be the matched routes, as recorded in the `http.route` attribute.
+    pub fn with_route_attributes(mut self, attributes: HashMap<String, Vec<KeyValue>>) -> Self {
+        self.route_attributes.extend(attributes);
+        self
+    }
+
+    /// set whether to record the `http.server.poll_ready.duration` histogram, default to `false`.
+    ///
+    /// it is the time from the first pending `poll_ready` of the inner service until it is ready,
+    /// in the duration unit, e.g. to see the backpressure of a concurrency limit or a load shedder
+    /// beneath the middleware. services which are always ready record nothing.
+    pub fn with_poll_ready_metric(mut self, enabled: bool) -> Self {
+        self.poll_ready_metric = enabled;
+        self
+    }
+
+    /// record the given entries of the OpenTelemetry baggage as attributes, e.g. `tenant.tier`,
+    /// the missing entries are omitted.
+    ///
+    /// the baggage is read from `opentelemetry::Context::current()` when the response is ready,
+    /// so the context has to be attached by an outer layer, e.g. one extracting the `baggage` header.
+    ///
+    /// every distinct value creates new series, only list the entries with a few known values,
+    /// never ids or free-form values which would blow up the cardinality of the metrics.
+    pub fn with_baggage_attributes(mut self, keys: Vec<String>) -> Self {
+        self.baggage_attributes.extend(keys);
+        self
+    }
+
+    /// set whether to insert the [MetricHandles] into the request extensions, default to `false`.
+    ///
+    /// it is required by the [MetricHandles] extractor, it costs a refcount bump and an extension
+    /// per request, so outside of the handlers prefer [HttpMetricsLayer::metric_handles].
+    pub fn with_metric_handles_extension(mut self, enabled: bool) -> Self {
+        self.metric_handles_extension = enabled;
+        self
+    }
+
+    /// build the layer, invalid histogram buckets are replaced by the default ones.
+    ///
+    /// # Panics
+    ///
+    /// panics if the prometheus exporter of `HttpMetricsLayerBuilder::with_dual_export` failed to register,
+    /// use [HttpMetricsLayerBuilder::try_build] to handle the error.
+    pub fn build(mut self) -> HttpMetricsLayer {
+        self.discard_invalid_buckets();
+        self.try_build().expect("failed to build the metrics layer")
+    }
+
+    /// discard the invalid histogram buckets, so that the default ones are used
+    fn discard_invalid_buckets(&mut self) {
+        self.duration_buckets = self.duration_buckets.take().filter(|b| valid_buckets(b));
+        self.size_buckets = self.size_buckets.take().filter(|b| valid_buckets(b));
+        self.request_size_buckets = self.request_size_buckets.take().filter(|b| valid_buckets(b));
+        self.response_size_buckets = self.response_size_buckets.take().filter(|b| valid_buckets(b));
+        self.route_duration_buckets.retain(|_, b| valid_buckets(b));
+    }
+
+    /// build the layer, creating the meter with the instrumentation scope from the given provider
+    fn build_with_provider(self, provider: &dyn MeterProvider) -> HttpMetricsLayer {
+        let scope = match &self.meter_scope {
+            Some((name, version)) => {
+                let builder = opentelemetry::InstrumentationScope::builder(name.clone());
+                match version {
+                    Some(version) => builder.with_version(version.clone()).build(),
+                    None => builder.build(),
+                }
+            }
+            None => opentelemetry::InstrumentationScope::builder(env!("CARGO_PKG_NAME"))
+                .with_version(env!("CARGO_PKG_VERSION"))
+                .build(),
+        };
+        let meter = provider.meter_with_scope(scope);
+
+        self.build_with_meter(meter)
+    }
+
+    /// build the layer, creating all the instruments from the given meter
+    fn build_with_meter(mut self, meter: Meter) -> HttpMetricsLayer {
+        self.discard_invalid_buckets();
+        let metadata = std::mem::take(&mut self.instrument_metadata);
+        let name = |name: &str| match &self.metric_prefix {
+            Some(prefix) => format!("{prefix}.{name}"),
+            None => name.to_string(),
+        };
+
+        let duration_unit = self.duration_unit;
+        let duration_description = format!("The HTTP request latencies in {}.", duration_unit.name());
+
+        let duration_buckets = self.duration_buckets.unwrap_or_else(|| {
+            HTTP_REQ_DURATION_HISTOGRAM_BUCKETS
+                .iter()
+                .map(|b| b * duration_unit.per_second())
+                .collect()
+        });
+        let size_buckets = self.size_buckets.unwrap_or_else(|| HTTP_REQ_SIZE_HISTOGRAM_BUCKETS.to_vec());
+        let req_size_buckets = self.request_size_buckets.unwrap_or_else(|| size_buckets.clone());
+        let res_size_buckets = self.response_size_buckets.unwrap_or(size_buckets);
+
+        let upgrade_duration = (self.duration_metric && self.websocket_mode == WsMode::Separate).then(|| {
+            meter
+                .f64_histogram(name("http.server.upgrade.duration"))
+                .with_unit(metadata.unit("http.server.upgrade.duration", duration_unit.symbol()))
+                .with_description(metadata.description(
+                    "http.server.upgrade.duration",
+                    format!("The HTTP upgrade request latencies in {}.", duration_unit.name()),
+                ))
+                .with_boundaries(duration_buckets.clone())
+                .build()
+        });
+
+        let stream_duration = (self.duration_metric && self.sse_mode == SseMode::Stream).then(|| {
+            meter
+                .f64_histogram(name("http.server.stream.duration"))
+                .with_unit(metadata.unit("http.server.stream.duration", duration_unit.symbol()))
+                .with_description(metadata.description(
+                    "http.server.stream.duration",
+                    format!("The HTTP event stream durations in {}.", duration_unit.name()),
+                ))
+                .with_boundaries(duration_buckets.clone())
+                .build()
+        });
+
+        let req_cpu_time = (self.cpu_time_metric && thread_cpu_time().is_some()).then(|| {
+            meter
+                .f64_histogram(name("http.server.request.cpu_time"))
+                .with_unit(metadata.unit("http.server.request.cpu_time", duration_unit.symbol()))
+                .with_description(metadata.description(
+                    "http.server.request.cpu_time",
+                    format!("The CPU time spent polling the HTTP requests in {}.", duration_unit.name()),
+                ))
+                .with_boundaries(duration_buckets.clone())
+                .build()
+        });
+
+        // request_duration_seconds
+        let req_duration = self.duration_metric.then(|| {
+            meter
+                .f64_histogram(name("http.server.request.duration"))
+                .with_unit(metadata.unit("http.server.request.duration", duration_unit.symbol()))
+                .with_description(metadata.description("http.server.request.duration", duration_description.clone()))
+                .with_boundaries(duration_buckets.clone())
+                .build()
+        });
+
+        let route_req_duration = self
+            .route_duration_buckets
+            .into_iter()
+            .filter(|_| self.duration_metric)
+            .map(|(route, buckets)| {
+                let histogram = meter
+                    .f64_histogram(name(&route_duration_instrument_name(&route)))
+                    .with_unit(metadata.unit("http.server.request.duration", duration_unit.symbol()))
+                    .with_description(metadata.description("http.server.request.duration", duration_description.clone()))
+                    .with_boundaries(buckets)
+                    .build();
+                (route, histogram)
+            })
+            .collect();
+
+        // request_size_bytes
+        let req_size = self.request_size_metric.then(|| {
+            meter
+                .u64_histogram(name("http.server.request.size"))
+                .with_unit(metadata.unit("http.server.request.size", "By"))
+                .with_description(metadata.description("http.server.request.size", "The HTTP request sizes in bytes."))
+                .with_boundaries(req_size_buckets.clone())
+                .build()
+        });
+
+        let res_size = self.response_size_metric.then(|| {
+            meter
+                .u64_histogram(name("http.server.response.size"))
+                .with_unit(metadata.unit("http.server.response.size", "By"))
+                .with_description(metadata.description("http.server.response.size", "The HTTP response sizes in bytes."))
+                .with_boundaries(res_size_buckets.clone())
+                .build()
+        });
+
+        let duration_clamped = self.max_recorded_duration.map(|_| {
+            meter
+                .u64_counter(name("http.server.duration.clamped"))
+                .with_unit(metadata.unit("http.server.duration.clamped", ""))
+                .with_description(metadata.description(
+                    "http.server.duration.clamped",
+                    "The total number of HTTP request durations clamped to the maximum recorded duration.",
+                ))
+                .build()
+        });
+
+        let poll_ready_duration = self.poll_ready_metric.then(|| {
+            meter
+                .f64_histogram(name("http.server.poll_ready.duration"))
+                .with_unit(metadata.unit("http.server.poll_ready.duration", duration_unit.symbol()))
+                .with_description(metadata.description(
+                    "http.server.poll_ready.duration",
+                    format!(
+                        "The time spent waiting for the service to become ready in {}.",
+                        duration_unit.name()
+                    ),
+                ))
+                .with_boundaries(duration_buckets.clone())
+                .build()
+        });
+
+        let unmatched_duration = (self.separate_unmatched_instrument && self.duration_metric).then(|| {
+            meter
+                .f64_histogram(name("http.server.unmatched.request.duration"))
+                .with_unit(metadata.unit("http.server.unmatched.request.duration", duration_unit.symbol()))
+                .with_description(metadata.description(
+                    "http.server.unmatched.request.duration",
+                    format!(
+                        "The latencies of the HTTP requests without a matched route in {}.",
+                        duration_unit.name()
+                    ),
+                ))
+                .with_boundaries(duration_buckets)
+                .build()
+        });
+
+        let unmatched_req_size = (self.separate_unmatched_instrument && self.request_size_metric).then(|| {
+            meter
+                .u64_histogram(name("http.server.unmatched.request.size"))
+                .with_unit(metadata.unit("http.server.unmatched.request.size", "By"))
+                .with_description(metadata.description(
+                    "http.server.unmatched.request.size",
+                    "The HTTP request sizes of the requests without a matched route in bytes.",
+                ))
+                .with_boundaries(req_size_buckets)
+                .build()
+        });
+
+        let unmatched_res_size = (self.separate_unmatched_instrument && self.response_size_metric).then(|| {
+            meter
+                .u64_histogram(name("http.server.unmatched.response.size"))
+                .with_unit(metadata.unit("http.server.unmatched.response.size", "By"))
+                .with_description(metadata.description(
+                    "http.server.unmatched.response.size",
+                    "The HTTP response sizes of the requests without a matched route in bytes.",
+                ))
+                .with_boundaries(res_size_buckets)
+                .build()
+        });
+
+        // no u64_up_down_counter because up_down_counter maybe < 0 since it allow negative values
+        let active_requests = Arc::new(AtomicI64::new(0));
+        let active_requests_mode = self.active_requests_metric.then_some(self.active_requests_mode);
+        let req_active = (active_requests_mode == Some(ActiveRequestsMode::UpDownCounter)).then(|| {
+            meter
+                .i64_up_down_counter(name("http.server.active_requests"))
+                .with_unit(metadata.unit("http.server.active_requests", ""))
+                .with_description(metadata.description("http.server.active_requests", "The number of active HTTP requests."))
+                .build()
+        });
+        let req_active_gauge = (active_requests_mode == Some(ActiveRequestsMode::Gauge)).then(|| {
+            let active_requests = active_requests.clone();
+            let labels = self.constant_labels.clone();
+            meter
+                .i64_observable_gauge(name("http.server.active_requests"))
+                .with_unit(metadata.unit("http.server.active_requests", ""))
+                .with_description(metadata.description("http.server.active_requests", "The number of active HTTP requests."))
+                .with_callback(move |observer| observer.observe(active_requests.load(Ordering::Relaxed), &labels))
+                .build()
+        });
+
+        let concurrency_utilization = self.concurrency_limit.filter(|&limit| limit > 0).map(|limit| {
+            let active_requests = active_requests.clone();
+            let labels = self.constant_labels.clone();
+            meter
+                .f64_observable_gauge(name("http.server.concurrency.utilization"))
+                .with_description(metadata.description(
+                    "http.server.concurrency.utilization",
+                    "The ratio of the active HTTP requests to the concurrency limit.",
+                ))
+                .with_unit(metadata.unit("http.server.concurrency.utilization", "1"))
+                .with_callback(move |observer| {
+                    observer.observe(active_requests.load(Ordering::Relaxed) as f64 / limit as f64, &labels)
+                })
+                .build()
+        });
+
+        let req_count = self.request_counter.then(|| {
+            meter
+                .u64_counter(name("http.server.request.count"))
+                .with_unit(metadata.unit("http.server.request.count", ""))
+                .with_description(metadata.description("http.server.request.count", "The total number of HTTP requests."))
+                .build()
+        });
+
+        let req_errors = self.error_counter.map(|_| {
+            meter
+                .u64_counter(name("http.server.request.errors"))
+                .with_unit(metadata.unit("http.server.request.errors", ""))
+                .with_description(metadata.description(
+                    "http.server.request.errors",
+                    "The total number of HTTP requests which failed.",
+                ))
+                .build()
+        });
+
+        let (slo_good, slo_bad) = if self.slos.is_empty() {
+            (None, None)
+        } else {
+            let slo_good = meter
+                .u64_counter(name("http.server.slo.good"))
+                .with_unit(metadata.unit("http.server.slo.good", ""))
+                .with_description(metadata.description(
+                    "http.server.slo.good",
+                    "The total number of HTTP requests which met the service level objective of the route.",
+                ))
+                .build();
+            let slo_bad = meter
+                .u64_counter(name("http.server.slo.bad"))
+                .with_unit(metadata.unit("http.server.slo.bad", ""))
+                .with_description(metadata.description(
+                    "http.server.slo.bad",
+                    "The total number of HTTP requests which missed the service level objective of the route.",
+                ))
+                .build();
+            (Some(slo_good), Some(slo_bad))
+        };
+
+        let req_failures = meter
+            .u64_counter(name("http.server.request.failures"))
+            .with_unit(metadata.unit("http.server.request.failures", ""))
+            .with_description(metadata.description(
+                "http.server.request.failures",
+                "The total number of HTTP requests which failed without producing a response.",
+            ))
+            .build();
+
+        let scrape_duration = self.self_observe.then(|| {
+            meter
+                .f64_histogram(name("http.server.scrape.duration"))
+                .with_unit(metadata.unit("http.server.scrape.duration", duration_unit.symbol()))
+                .with_description(metadata.description(
+                    "http.server.scrape.duration",
+                    format!("The metrics endpoint scrape latencies in {}.", duration_unit.name()),
+                ))
+                .with_boundaries(
+                    HTTP_REQ_DURATION_HISTOGRAM_BUCKETS
+                        .iter()
+                        .map(|b| b * duration_unit.per_second())
+                        .collect(),
+                )
+                .build()
+        });
+
+        let res_ttfb = self.ttfb.then(|| {
+            meter
+                .f64_histogram(name("http.server.response.time_to_first_byte"))
+                .with_unit(metadata.unit("http.server.response.time_to_first_byte", duration_unit.symbol()))
+                .with_description(metadata.description(
+                    "http.server.response.time_to_first_byte",
+                    format!("The HTTP response time to first byte in {}.", duration_unit.name()),
+                ))
+                .with_boundaries(
+                    HTTP_REQ_DURATION_HISTOGRAM_BUCKETS
+                        .iter()
+                        .map(|b| b * duration_unit.per_second())
+                        .collect(),
+                )
+                .build()
+        });
+
+        let phase_histogram = |instrument: &str, description: String| {
+            meter
+                .f64_histogram(name(instrument))
+                .with_unit(metadata.unit(instrument, duration_unit.symbol()))
+                .with_description(metadata.description(instrument, description))
+                .with_boundaries(
+                    HTTP_REQ_DURATION_HISTOGRAM_BUCKETS
+                        .iter()
+                        .map(|b| b * duration_unit.per_second())
+                        .collect(),
+                )
+                .build()
+        };
+        let handler_duration = self.phase_breakdown.then(|| {
+            phase_histogram(
+                "http.server.handler.duration",
+                format!(
+                    "The time spent in the handler until the response headers in {}.",
+                    duration_unit.name()
+                ),
+            )
+        });
+        let res_body_duration = self.phase_breakdown.then(|| {
+            phase_histogram(
+                "http.server.response.body.duration",
+                format!(
+                    "The time from the first to the last chunk of the HTTP response body in {}.",
+                    duration_unit.name()
+                ),
+            )
+        });
+
+        let req_header_size = self.header_size_metric.then(|| {
+            meter
+                .u64_histogram(name("http.server.request.header.size"))
+                .with_unit(metadata.unit("http.server.request.header.size", "By"))
+                .with_description(
+                    metadata.description("http.server.request.header.size", "The HTTP request header sizes in bytes."),
+                )
+                .with_boundaries(HTTP_REQ_HEADER_SIZE_HISTOGRAM_BUCKETS.to_vec())
+                .build()
+        });
+
+        let meter_state = MetricState {
+            metric: Arc::new(Metric {
+                req_duration,
+                route_req_duration,
+                req_size,
+                res_size,
+                req_active,
+                req_active_gauge,
+                concurrency_utilization,
+                req_count,
+                req_errors,
+                slo_good,
+                slo_bad,
+                req_failures,
+                scrape_duration,
+                res_ttfb,
+                upgrade_duration,
+                stream_duration,
+                req_cpu_time,
+                req_header_size,
+                handler_duration,
+                res_body_duration,
+                unmatched_duration,
+                unmatched_req_size,
+                unmatched_res_size,
+                duration_clamped,
+                poll_ready_duration,
+            }),
+            skipper: self.skipper,
+            is_tls: self.is_tls,
+            unmatched_route_label: self.unmatched_route_label,
+            attributes_from_request: self.attributes_from_request,
+            attributes_from_response: self.attributes_from_response,
+            status_code_attribute: self.status_code_attribute,
+            status_class_attribute: self.status_class_attribute,
+            exact_response_size: self.exact_response_size,
+            exact_request_size: self.exact_request_size,
+            server_port_attribute: self.server_port_attribute,
+            protocol_version_attribute: self.protocol_version_attribute,
+            user_agent_classifier: self.user_agent_classifier,
+            duration_unit: self.duration_unit,
+            error_counter: self.error_counter,
+            client_address: self.client_address,
+            client_address_normalizer: self.client_address_normalizer,
+            server_address_attribute: self.server_address_attribute,
+            server_address_normalizer: self.server_address_normalizer,
+            route_normalizer: self.route_normalizer,
+            server_address_fallback: self.server_address_fallback,
+            trust_forwarded_headers: self.trust_forwarded_headers,
+            active_requests,
+            route_mode: self.route_mode,
+            include_query_string: self.include_query_string,
+            request_content_type_attribute: self.request_content_type_attribute,
+            response_content_type_attribute: self.response_content_type_attribute,
+            content_type_allow_list: self.content_type_allow_list,
+            grpc_status_attribute: self.grpc_status_attribute,
+            client_port_attribute: self.client_port_attribute,
+            sampling_ratio: self.sampling_ratio,
+            method_normalization: self.method_normalization,
+            request_skipper: self.request_skipper,
+            active_requests_route_label: self.active_requests_route_label,
+            constant_labels: self.constant_labels,
+            slos: self.slos,
+            connection_reused_attribute: self.connection_reused_attribute,
+            attribute_filter: self.attribute_filter,
+            websocket_mode: self.websocket_mode,
+            resend_count_header: self.resend_count_header,
+            sse_mode: self.sse_mode,
+            classifier: self.classifier,
+            error_type_attribute: self.error_type_attribute,
+            detailed_attributes_on_error_only: self.detailed_attributes_on_error_only,
+            scheme_attribute: self.scheme_attribute,
+            separate_unmatched_instrument: self.separate_unmatched_instrument,
+            original_route_attribute: self.original_route_attribute,
+            active_requests_attributes: self.active_requests_attributes,
+            max_recorded_duration: self.max_recorded_duration,
+            size_metrics_on_success_only: self.size_metrics_on_success_only,
+            route_attributes: self.route_attributes,
+            baggage_attributes: self
+                .baggage_attributes
+                .into_iter()
+                .map(|key| Arc::<str>::from(key).into())
+                .collect(),
+            metric_handles_extension: self.metric_handles_extension,
+        };
+
+        HttpMetricsLayer {
+            state: Arc::new(meter_state),
+            #[cfg(feature = "prometheus")]
+            registry: self.registry,
+            #[cfg(feature = "prometheus")]
+            metrics_format: self.metrics_format,
+            provider: None,
+        }
+    }
+}
+
+/// a metric reader set by [HttpMetricsLayerBuilder::with_metric_reader],
+/// overriding the temporality of the inner reader if [HttpMetricsLayerBuilder::with_temporality] is set
+#[derive(Debug)]
+struct LayerReader {
+    inner: Arc<dyn MetricReader>,
+    temporality: Option<Temporality>,
+}
+
+impl MetricReader for LayerReader {
+    fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+        self.inner.register_pipeline(pipeline)
+    }
+
+    fn collect(&self, rm: &mut ResourceMetrics) -> MetricResult<()> {
+        self.inner.collect(rm)
+    }
+
+    fn force_flush(&self) -> MetricResult<()> {
+        self.inner.force_flush()
+    }
+
+    fn shutdown(&self) -> MetricResult<()> {
+        self.inner.shutdown()
+    }
+
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        self.temporality.unwrap_or_else(|| self.inner.temporality(kind))
+    }
+}
+
+/// the name of the request duration instrument for a route with custom buckets
+fn route_duration_instrument_name(route: &str) -> String {
+    let route: String = route
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("http.server.request.duration.{}", route.trim_matches('_'))
+}
+
+impl<S> Layer<S> for HttpMetricsLayer {
+    type Service = HttpMetrics<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        HttpMetrics {
+            state: self.state.clone(),
+            service,
+            pending_since: None,
+        }
+    }
+}
+
+pin_project! {
     /// Response future for [`HttpMetrics`] Service.
     pub struct ResponseFuture<F> {
         #[pin]
-        inner: F,
-        start: Instant,
-        state: MetricState,
-        path: String,
-        method: String,
-        url_scheme: String,
-        host: String,
-        req_size: u64,
+        inner: F,
+        start: Instant,
+        state: Arc<MetricState>,
+        path: String,
+        // the attributes of the active requests, built once in `call()` and reused verbatim for the decrement,
+        // on completion or on drop, so the counter always balances to zero
+        active_attributes: Vec<KeyValue>,
+        // the attributes of the histograms except the response related ones, `http.request.method` first,
+        // then `http.route` and/or `http.target` as set by the [RouteMode] (`http.route` first with `Both`),
+        // so the first two are the method and `http.route`, or `http.target` with `RawPath`
+        labels: Vec<KeyValue>,
+        req_size: u64,
+        req_header_size: u64,
+        // the counted request body size, only when the exact request size is enabled
+        req_body_size: Option<Arc<RequestBodySize>>,
+        // whether the active requests has been decremented
+        recorded: bool,
+        // whether the request is sampled, see [HttpMetricsLayerBuilder::with_sampling_ratio]
+        sampled: bool,
+        // whether the request is skipped before any metric is recorded
+        skipped: bool,
+        // the thread CPU time spent polling the inner future, only when the CPU time metric is enabled
+        cpu_time: Option<Duration>,
+        // the classifier of the response, only when a classifier is set and the request is sampled
+        classifier: Option<Box<dyn DynClassifyResponse>>,
+        // whether the request is recorded into the unmatched instruments
+        unmatched: bool,
+    }
+
+    impl<F> PinnedDrop for ResponseFuture<F> {
+        fn drop(this: Pin<&mut Self>) {
+            let this = this.project();
+            // the future is dropped before completion, e.g. the client disconnected
+            if !*this.recorded {
+                this.state.active_requests.fetch_sub(1, Ordering::Relaxed);
+                if let Some(req_active) = &this.state.metric.req_active {
+                    req_active.add(-1, this.active_attributes);
+                }
+            }
+        }
+    }
+}
+
+/// the middleware is generic over both the request and the response bodies, so it fits anywhere
+/// in a tower stack of http services, e.g. beneath or above a `tower_http::trace::TraceLayer`.
+/// the request body is wrapped in a [RequestBody] and the response body in a [ResponseBody] to measure them,
+/// both are transparent wrappers unless the exact sizes are enabled.
+impl<S, R, ResBody> Service<Request<R>> for HttpMetrics<S>
+where
+    S: Service<Request<RequestBody<R>>, Response = Response<ResBody>>,
+    R: httpBody,
+    ResBody: httpBody,
+{
+    type Response = Response<ResponseBody<ResBody>>;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let Some(poll_ready_duration) = &self.state.metric.poll_ready_duration else {
+            return self.service.poll_ready(cx);
+        };
+
+        let poll = self.service.poll_ready(cx);
+        if poll.is_pending() {
+            self.pending_since.get_or_insert_with(Instant::now);
+        } else if let Some(since) = self.pending_since.take() {
+            let waited = since.elapsed().as_secs_f64() * self.state.duration_unit.per_second();
+            let labels = filter_labels(
+                &self.state.attribute_filter,
+                "http.server.poll_ready.duration",
+                &self.state.constant_labels,
+            );
+            poll_ready_duration.record(waited, &labels);
+        }
+        poll
+    }
+
+    fn call(&mut self, mut req: Request<R>) -> Self::Future {
+        let start = Instant::now();
+        if self.state.metric_handles_extension {
+            req.extensions_mut().insert(MetricHandles(self.state.metric.clone()));
+        }
+        let matched_path = req.extensions().get::<MatchedPath>();
+        let route_matched = matched_path.is_some();
+        let path = if let Some(matched_path) = matched_path {
+            match &self.state.route_normalizer {
+                Some(normalize) => normalize(matched_path.as_str()).into_owned(),
+                None => matched_path.as_str().to_owned(),
+            }
+        } else {
+            self.state.unmatched_route_label.clone()
+        };
+        let method_attribute = if self.state.method_normalization && !is_standard_method(req.method()) {
+            KeyValue::new("http.request.method", "_OTHER")
+        } else {
+            KeyValue::new("http.request.method", method_value(req.method()))
+        };
+
+        // the skipped requests are not recorded at all, not even as active requests
+        let (parts, body) = req.into_parts();
+        let skipped = self.state.skipper.skips(&parts.method, &path)
+            || self
+                .state
+                .request_skipper
+                .as_ref()
+                .is_some_and(|skipper| (skipper.skip)(&parts));
+        let req = Request::from_parts(parts, body);
+        if skipped {
+            // only the metrics endpoint scrapes are recorded, see [HttpMetricsLayerBuilder::with_self_observe]
+            let labels = match &self.state.metric.scrape_duration {
+                Some(_) if path.starts_with(METRICS_PATH) => {
+                    let mut labels = vec![method_attribute, KeyValue::new("http.route", path.clone())];
+                    labels.extend(self.state.constant_labels.iter().cloned());
+                    labels
+                }
+                _ => Vec::new(),
+            };
+            return ResponseFuture {
+                inner: self.service.call(req.map(RequestBody::new)),
+                start,
+                state: self.state.clone(),
+                path,
+                active_attributes: Vec::new(),
+                labels,
+                req_size: 0,
+                req_header_size: 0,
+                req_body_size: None,
+                recorded: true,
+                sampled: false,
+                skipped: true,
+                cpu_time: None,
+                classifier: None,
+                unmatched: false,
+            };
+        }
+
+        // ref https://github.com/open-telemetry/semantic-conventions/blob/main/docs/http/http-metrics.md#metric-httpserveractive_requests
+        // http.request.method and url.scheme is required, they can be left out by `with_scheme_attribute`
+        // and `with_active_requests_attributes`
+        // the same attributes are used to decrement the counter, so it balances to zero
+        let mut active_attributes = Vec::with_capacity(2);
+        if self.state.active_requests_attributes != ActiveRequestsAttributes::None {
+            active_attributes.push(method_attribute.clone());
+        }
+        let scheme_active_attribute =
+            self.state.scheme_attribute && self.state.active_requests_attributes == ActiveRequestsAttributes::MethodScheme;
+        // the scheme is resolved once, it is both the url.scheme attribute and the default of server.port
+        let url_scheme = (scheme_active_attribute || self.state.server_port_attribute).then(|| {
+            if let Some(TlsConnection(tls)) = req.extensions().get::<TlsConnection>() {
+                StringValue::from(if *tls { "https" } else { "http" })
+            } else if self.state.is_tls {
+                StringValue::from("https")
+            } else if !self.state.trust_forwarded_headers {
+                StringValue::from("http")
+            } else {
+                (|| {
+                    // headers with invalid values fall through to the next heuristic
+                    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+                    if let Some(scheme) = header("X-Forwarded-Proto").or_else(|| header("X-Forwarded-Protocol")) {
+                        return scheme_value(scheme);
+                    }
+                    if header("X-Forwarded-Ssl") == Some("on") {
+                        return StringValue::from("https");
+                    }
+                    match header("X-Url-Scheme") {
+                        Some(scheme) => scheme_value(scheme),
+                        None => StringValue::from("http"),
+                    }
+                })()
+            }
+        });
+        if scheme_active_attribute {
+            if let Some(url_scheme) = &url_scheme {
+                active_attributes.push(KeyValue::new("url.scheme", url_scheme.clone()));
+            }
+        }
+        if self.state.active_requests_route_label && route_matched {
+            active_attributes.push(KeyValue::new("http.route", path.clone()));
+        }
+        active_attributes.extend(self.state.constant_labels.iter().cloned());
+        if self.state.attribute_filter.is_some() {
+            active_attributes = filter_labels(
+                &self.state.attribute_filter,
+                "http.server.active_requests",
+                &active_attributes,
+            )
+            .into_owned();
+        }
+        self.state.active_requests.fetch_add(1, Ordering::Relaxed);
+        let sampled = self.state.sampling_ratio >= 1.0 || sample_random() < self.state.sampling_ratio;
+        if let Some(req_active) = &self.state.metric.req_active {
+            req_active.add(1, &active_attributes);
+        }
+
+        let host = req
+            .uri()
+            .authority()
+            .map(|a| a.as_str())
+            .or_else(|| req.headers().get(http::header::HOST).and_then(|h| h.to_str().ok()));
+
+        // room for the response attributes pushed in `poll()`, so they never reallocate
+        let mut labels = Vec::with_capacity(16 + self.state.constant_labels.len());
+        labels.push(method_attribute);
+        // the route attribute always comes right after the method
+        // the uri before a nested router stripped its prefix, e.g. `/sub/sub1` rather than `/sub1`
+        let uri = match req.extensions().get::<OriginalUri>() {
+            Some(OriginalUri(uri)) => uri,
+            None => req.uri(),
+        };
+        let target = || {
+            let target = match uri.path_and_query() {
+                Some(path_and_query) if self.state.include_query_string => path_and_query.as_str(),
+                _ => uri.path(),
+            };
+            KeyValue::new("http.target", target.to_string())
+        };
+        match self.state.route_mode {
+            RouteMode::Template => labels.push(KeyValue::new("http.route", path.clone())),
+            RouteMode::RawPath => labels.push(target()),
+            RouteMode::Both => {
+                labels.push(KeyValue::new("http.route", path.clone()));
+                labels.push(target());
+            }
+        }
+        if self.state.original_route_attribute {
+            let original = match &self.state.route_normalizer {
+                Some(normalize) => normalize(uri.path()).into_owned(),
+                None => uri.path().to_string(),
+            };
+            labels.push(KeyValue::new("http.route.original", original));
+        }
+
+        if self.state.server_port_attribute {
+            let default_port = match url_scheme.as_ref().map(|s| s.as_str()) {
+                Some("https") => 443,
+                _ => 80,
+            };
+            let port = host.and_then(|h| split_host_port(h).1).unwrap_or(default_port);
+            labels.push(KeyValue::new("server.port", port as i64));
+        }
+
+        // server.address: Name of the local HTTP server that received the request.
+        // Determined by using the first of the following that applies
+        //
+        // 1. The primary server name of the matched virtual host. MUST only include host identifier.
+        // 2. Host identifier of the request target if it's sent in absolute-form.
+        // 3. Host identifier of the Host header
+        if self.state.server_address_attribute {
+            let host = match host {
+                Some(host) => Some(split_host_port(host).0),
+                // an empty fallback omits the attribute
+                None => Some(self.state.server_address_fallback.as_str()).filter(|fallback| !fallback.is_empty()),
+            };
+            if let Some(host) = host {
+                let host = match &self.state.server_address_normalizer {
+                    Some(normalize) => normalize(host),
+                    None => host.to_string(),
+                };
+                labels.push(KeyValue::new("server.address", host));
+            }
+        }
+
+        if self.state.protocol_version_attribute {
+            labels.push(KeyValue::new("network.protocol.version", protocol_version(req.version())));
+        }
+
+        if let Some(address) = self.state.client_address.and_then(|source| source.resolve(&req)) {
+            let address = match &self.state.client_address_normalizer {
+                Some(normalize) => normalize(&address),
+                None => address,
+            };
+            labels.push(KeyValue::new("client.address", address));
+        }
+
+        if self.state.client_port_attribute && self.state.client_address == Some(ClientAddrSource::ConnectInfo) {
+            if let Some(ConnectInfo(addr)) = req.extensions().get::<ConnectInfo<SocketAddr>>() {
+                labels.push(KeyValue::new("client.port", addr.port() as i64));
+            }
+        }
+
+        if let Some(header) = &self.state.resend_count_header {
+            let count = req
+                .headers()
+                .get(header)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .unwrap_or(0);
+            labels.push(KeyValue::new("http.request.resend_count", resend_count_bucket(count)));
+        }
+
+        if self.state.connection_reused_attribute {
+            if let Some(ConnectionReused(reused)) = req.extensions().get::<ConnectionReused>() {
+                labels.push(KeyValue::new("http.connection.reused", *reused));
+            }
+        }
+
+        if self.state.request_content_type_attribute {
+            if let Some(content_type) = content_type_value(req.headers(), &self.state.content_type_allow_list) {
+                labels.push(KeyValue::new("http.request.content_type", content_type));
+            }
+        }
+
+        if let Some(classify) = &self.state.user_agent_classifier {
+            let user_agent = req
+                .headers()
+                .get(http::header::USER_AGENT)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("");
+            labels.push(KeyValue::new("user_agent.category", classify(user_agent)));
+        }
+
+        if let Some(attributes) = self.state.route_attributes.get(&path).filter(|_| route_matched) {
+            labels.extend(attributes.iter().cloned());
+        }
+
+        labels.extend(self.state.constant_labels.iter().cloned());
+
+        let req_header_size = match &self.state.metric.req_header_size {
+            Some(_) if sampled => compute_request_header_size(&req) as u64,
+            _ => 0,
+        };
+
+        let (req, req_size, req_body_size) = if self.state.metric.req_size.is_none() || !sampled {
+            (req.map(RequestBody::new), 0, None)
+        } else if self.state.exact_request_size {
+            let req_size = compute_request_head_size(&req);
+            let req_body_size = Arc::new(RequestBodySize::default());
+            let req = req.map(|body| RequestBody {
+                inner: body,
+                size: Some(req_body_size.clone()),
+            });
+            (req, req_size, Some(req_body_size))
+        } else {
+            let req_size = compute_approximate_request_size(&req);
+            (req.map(RequestBody::new), req_size, None)
+        };
+
+        let req = match &self.state.attributes_from_request {
+            Some(f) => {
+                let (parts, body) = req.into_parts();
+                labels.extend(f(&parts));
+                Request::from_parts(parts, body)
+            }
+            None => req,
+        };
+
+        let (req, classifier) = match self.state.classifier.as_ref().filter(|_| sampled) {
+            Some(make) => {
+                let (parts, body) = req.into_parts();
+                let head = Request::from_parts(parts, ());
+                let classifier = make.make_classifier(&head);
+                (Request::from_parts(head.into_parts().0, body), Some(classifier))
+            }
+            None => (req, None),
+        };
+
+        // for scheme, see github.com/labstack/echo/v4@v4.11.1/context.go
+        // we can not use req.uri().scheme() since for non-absolute uri, it is always None
+
+        ResponseFuture {
+            inner: self.service.call(req),
+            recorded: false,
+            sampled,
+            skipped: false,
+            cpu_time: (sampled && self.state.metric.req_cpu_time.is_some()).then_some(Duration::ZERO),
+            classifier,
+            unmatched: self.state.separate_unmatched_instrument && !route_matched,
+            start,
+            path,
+            active_attributes,
+            labels,
+            req_size: req_size as u64,
+            req_header_size,
+            req_body_size,
+            state: self.state.clone(),
+        }
+    }
+}
+
+/// the standard HTTP methods, see [RFC9110](https://www.rfc-editor.org/rfc/rfc9110.html#name-methods)
+/// and [RFC5789](https://www.rfc-editor.org/rfc/rfc5789.html)
+const STANDARD_METHODS: &[&str] = &["GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH"];
+
+/// whether the method is one of the standard methods known by the semantic conventions
+fn is_standard_method(method: &http::Method) -> bool {
+    STANDARD_METHODS.contains(&method.as_str())
+}
+
+/// the `http.request.method` value, the standard methods are static strings to avoid allocation
+fn method_value(method: &http::Method) -> StringValue {
+    match STANDARD_METHODS.iter().find(|m| **m == method.as_str()) {
+        Some(m) => StringValue::from(*m),
+        None => StringValue::from(method.as_str().to_string()),
+    }
+}
+
+/// the `http.response.status_code` value, the common status codes are static strings to avoid allocation
+fn status_code_value(status: http::StatusCode) -> StringValue {
+    match status.as_u16() {
+        200 => StringValue::from("200"),
+        201 => StringValue::from("201"),
+        204 => StringValue::from("204"),
+        301 => StringValue::from("301"),
+        302 => StringValue::from("302"),
+        304 => StringValue::from("304"),
+        400 => StringValue::from("400"),
+        401 => StringValue::from("401"),
+        403 => StringValue::from("403"),
+        404 => StringValue::from("404"),
+        500 => StringValue::from("500"),
+        502 => StringValue::from("502"),
+        503 => StringValue::from("503"),
+        code => StringValue::from(code.to_string()),
+    }
+}
+
+/// the `url.scheme` value, `http` and `https` are static strings to avoid allocation
+fn scheme_value(scheme: &str) -> StringValue {
+    match scheme {
+        "http" => StringValue::from("http"),
+        "https" => StringValue::from("https"),
+        _ => StringValue::from(scheme.to_string()),
+    }
+}
+
+/// the `network.protocol.version` value of the HTTP version
+fn protocol_version(version: http::Version) -> &'static str {
+    match version {
+        http::Version::HTTP_09 => "0.9",
+        http::Version::HTTP_10 => "1.0",
+        http::Version::HTTP_11 => "1.1",
+        http::Version::HTTP_2 => "2",
+        http::Version::HTTP_3 => "3",
+        _ => "unknown",
+    }
+}
+
+/// split the `Host` header value into the host and the port,
+/// IPv6 literals are enclosed in brackets, e.g. `[::1]:8080`
+fn split_host_port(host: &str) -> (&str, Option<u16>) {
+    if let Some(rest) = host.strip_prefix('[') {
+        return match rest.split_once(']') {
+            Some((ip, port)) => (ip, port.strip_prefix(':').and_then(|p| p.parse().ok())),
+            None => (host, None),
+        };
+    }
+    match host.rsplit_once(':') {
+        Some((h, port)) => match port.parse() {
+            Ok(port) => (h, Some(port)),
+            Err(_) => (host, None),
+        },
+        None => (host, None),
+    }
+}
+
+/// compute approximate request size
+///
+/// the implementation refs [labstack/echo-contrib 's prometheus middleware](https://github.com/labstack/echo-contrib/blob/db8911a1af7abb6bdafbd999adada548fd9c0849/echoprometheus/prometheus.go#L329)
+fn compute_approximate_request_size<T>(req: &Request<T>) -> usize {
+    compute_request_head_size(req) + compute_request_body_size(req)
+}
+
+/// compute the size of the request line and headers
+fn compute_request_head_size<T>(req: &Request<T>) -> usize {
+    let mut s = 0;
+    s += req.uri().path().len();
+    s += req.method().as_str().len();
+    s += compute_request_header_size(req);
+
+    s += req.uri().host().map(|h| h.len()).unwrap_or(0);
+    s
+}
+
+/// compute the size of the request headers, the sum of the header name and value lengths
+fn compute_request_header_size<T>(req: &Request<T>) -> usize {
+    req.headers().iter().map(|(k, v)| k.as_str().len() + v.as_bytes().len()).sum()
+}
+
+/// compute the request body size from the `Content-Length` header
+fn compute_request_body_size<T>(req: &Request<T>) -> usize {
+    content_length(req.headers()).unwrap_or(0) as usize
+}
+
+/// the value of the `Content-Length` header, `None` if it is missing or invalid
+fn content_length(headers: &http::HeaderMap) -> Option<u64> {
+    headers.get(http::header::CONTENT_LENGTH)?.to_str().ok()?.parse().ok()
+}
+
+/// whether the response is a server-sent events stream
+fn is_event_stream(headers: &http::HeaderMap) -> bool {
+    headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim_start().to_ascii_lowercase().starts_with("text/event-stream"))
+}
+
+/// the media type of the `Content-Type` header without the parameters,
+/// or `other` if it is not in the allow list
+fn content_type_value(headers: &http::HeaderMap, allow_list: &Option<Vec<String>>) -> Option<String> {
+    let content_type = headers.get(http::header::CONTENT_TYPE)?.to_str().ok()?;
+    let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    match allow_list {
+        Some(allow_list) if !allow_list.contains(&media_type) => Some("other".to_string()),
+        _ => Some(media_type),
+    }
+}
+
+/// a fast thread-local xorshift random number in `[0, 1)`, for the request sampling
+fn sample_random() -> f64 {
+    thread_local! {
+        static STATE: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish() | 1);
+    }
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    })
+}
+
+/// the `grpc-status` header (or trailer) value
+fn grpc_status(headers: &http::HeaderMap) -> Option<String> {
+    headers
+        .get("grpc-status")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+}
+
+/// whether the response is a gRPC response, by the `Content-Type` header
+fn is_grpc(headers: &http::HeaderMap) -> bool {
+    headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/grpc"))
+}
+
+/// the CPU time consumed by the current thread, `None` if unsupported
+#[cfg(all(feature = "cpu-time", unix))]
+fn thread_cpu_time() -> Option<Duration> {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // SAFETY: `ts` is a valid, exclusively borrowed timespec for the duration of the call
+    let ret = unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) };
+    (ret == 0).then(|| Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+/// the CPU time consumed by the current thread, `None` if unsupported
+#[cfg(not(all(feature = "cpu-time", unix)))]
+fn thread_cpu_time() -> Option<Duration> {
+    None
+}
+
+/// the bucket of the `http.request.resend_count` attribute
+fn resend_count_bucket(count: u64) -> &'static str {
+    match count {
+        0 => "0",
+        1 => "1",
+        2 => "2",
+        _ => "3+",
+    }
+}
+
+/// the labels kept by the [AttributeFilterFn] for the instrument
+fn filter_labels<'a>(filter: &Option<AttributeFilterFn>, instrument: &str, labels: &'a [KeyValue]) -> Cow<'a, [KeyValue]> {
+    match filter {
+        Some(filter) => labels.iter().filter(|kv| filter(instrument, kv)).cloned().collect(),
+        None => Cow::Borrowed(labels),
+    }
+}
+
+/// the class of the status code, e.g. `2xx`, `5xx`
+fn status_class(status: http::StatusCode) -> &'static str {
+    match status.as_u16() {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
+}
+
+impl<F, B: httpBody, E> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+{
+    type Output = Result<Response<ResponseBody<B>>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        // only read the thread CPU time of the sampled requests of an enabled metric, it is a syscall
+        let cpu_start = this.cpu_time.is_some().then(thread_cpu_time).flatten();
+        let result = this.inner.poll(cx);
+        if let Some(cpu_time) = this.cpu_time.as_mut() {
+            if let (Some(start), Some(end)) = (cpu_start, thread_cpu_time()) {
+                *cpu_time += end.saturating_sub(start);
+            }
+        }
+        let result = ready!(result);
+
+        if *this.skipped {
+            if let Some(scrape_duration) = &this.state.metric.scrape_duration {
+                if !this.labels.is_empty() {
+                    let latency = this.start.elapsed().as_secs_f64() * this.state.duration_unit.per_second();
+                    let labels = filter_labels(&this.state.attribute_filter, "http.server.scrape.duration", this.labels);
+                    scrape_duration.record(latency, &labels);
+                }
+            }
+            return Ready(result.map(|response| response.map(ResponseBody::new)));
+        }
+
+        // the request is finished whether the inner service succeeded or not
+        this.state.active_requests.fetch_sub(1, Ordering::Relaxed);
+        if let Some(req_active) = &this.state.metric.req_active {
+            req_active.add(-1, this.active_attributes);
+        }
+        *this.recorded = true;
+
+        let response = match result {
+            Ok(response) => response,
+            Err(err) => {
+                if *this.sampled {
+                    // http.request.method, the route attribute, error.type and the constant labels
+                    let mut labels = this.labels[..2].to_vec();
+                    if this.state.error_type_attribute {
+                        labels.push(KeyValue::new("error.type", std::any::type_name::<E>()));
+                    }
+                    labels.extend(this.state.constant_labels.iter().cloned());
+                    let labels = filter_labels(&this.state.attribute_filter, "http.server.request.failures", &labels);
+                    this.state.metric.req_failures.add(1, &labels);
+                }
+                return Ready(Err(err));
+            }
+        };
+
+        let mut response = response.map(ResponseBody::new);
+
+        let mut elapsed = this.start.elapsed();
+        let clamped = this.state.max_recorded_duration.filter(|max| elapsed > *max);
+        if let Some(max) = clamped {
+            elapsed = max;
+        }
+        let latency = elapsed.as_secs_f64() * this.state.duration_unit.per_second();
+
+        if !*this.sampled {
+            return Poll::Ready(Ok(response));
+        }
+
+        if let (Some(counter), Some(_)) = (&this.state.metric.duration_clamped, clamped) {
+            // http.request.method, the route attribute and the constant labels
+            let labels = [&this.labels[..2], &this.state.constant_labels[..]].concat();
+            counter.add(
+                1,
+                &filter_labels(&this.state.attribute_filter, "http.server.duration.clamped", &labels),
+            );
+        }
+
+        if *this.unmatched {
+            // http.request.method, http.response.status_code and the constant labels
+            let mut labels = vec![
+                this.labels[0].clone(),
+                KeyValue::new("http.response.status_code", status_code_value(response.status())),
+            ];
+            labels.extend(this.state.constant_labels.iter().cloned());
+            let filter = &this.state.attribute_filter;
+            if let Some(duration) = &this.state.metric.unmatched_duration {
+                let labels = filter_labels(filter, "http.server.unmatched.request.duration", &labels);
+                duration.record(latency, &labels);
+            }
+            let skip_body_sizes = this.state.size_metrics_on_success_only && !response.status().is_success();
+            if let Some(req_size) = this.state.metric.unmatched_req_size.as_ref().filter(|_| !skip_body_sizes) {
+                let recorder = BodySizeRecorder {
+                    histogram: req_size.clone(),
+                    labels: labels.clone(),
+                    size: *this.req_size,
+                    instrument: "http.server.unmatched.request.size",
+                    attribute_filter: filter.clone(),
+                };
+                recorder.record_request(this.req_body_size.take());
+            }
+            if let Some(histogram) = this.state.metric.unmatched_res_size.as_ref().filter(|_| !skip_body_sizes) {
+                let size = response_size(&mut response, this.state.exact_response_size, || BodySizeRecorder {
+                    histogram: histogram.clone(),
+                    labels: labels.clone(),
+                    size: 0,
+                    instrument: "http.server.unmatched.response.size",
+                    attribute_filter: filter.clone(),
+                });
+                if let Some(size) = size {
+                    histogram.record(size, &filter_labels(filter, "http.server.unmatched.response.size", &labels));
+                }
+            }
+            return Poll::Ready(Ok(response));
+        }
+
+        if let Some(slo) = this.state.slos.get(this.path.as_str()) {
+            let good =
+                elapsed <= slo.latency_threshold && slo.good_status_classes.contains(&StatusClass::of(response.status()));
+            let (counter, instrument) = if good {
+                (&this.state.metric.slo_good, "http.server.slo.good")
+            } else {
+                (&this.state.metric.slo_bad, "http.server.slo.bad")
+            };
+            if let Some(counter) = counter {
+                // http.request.method, the route attribute and the constant labels
+                let labels = [&this.labels[..2], &this.state.constant_labels[..]].concat();
+                counter.add(1, &filter_labels(&this.state.attribute_filter, instrument, &labels));
+            }
+        }
+
+        let mut labels = std::mem::take(this.labels);
+        if this.state.status_code_attribute {
+            labels.push(KeyValue::new(
+                "http.response.status_code",
+                status_code_value(response.status()),
+            ));
+        }
+        if this.state.status_class_attribute {
+            labels.push(KeyValue::new("http.response.status_class", status_class(response.status())));
+        }
+        if this.state.response_content_type_attribute {
+            if let Some(content_type) = content_type_value(response.headers(), &this.state.content_type_allow_list) {
+                labels.push(KeyValue::new("http.response.content_type", content_type));
+            }
+        }
+        if let Some(f) = &this.state.attributes_from_response {
+            let (parts, body) = response.into_parts();
+            labels.extend(f(&parts));
+            response = Response::from_parts(parts, body);
+        }
+        if !this.state.baggage_attributes.is_empty() {
+            let cx = opentelemetry::Context::current();
+            let baggage = cx.baggage();
+            labels.extend(this.state.baggage_attributes.iter().filter_map(|key| {
+                baggage
+                    .get(key.as_str())
+                    .map(|value| KeyValue::new(key.clone(), value.clone()))
+            }));
+        }
+        let grpc_status = this
+            .state
+            .grpc_status_attribute
+            .then(|| grpc_status(response.headers()))
+            .flatten();
+        if let Some(status) = &grpc_status {
+            labels.push(KeyValue::new("rpc.grpc.status_code", status.clone()));
+        }
+        let classified = this.classifier.is_some();
+        let mut classify_eos = None;
+        let mut error_type = None;
+        if let Some(classifier) = this.classifier.take() {
+            let (parts, body) = response.into_parts();
+            let head = Response::from_parts(parts, ());
+            match classifier.classify_response(&head) {
+                Classification::Ready(failure) => error_type = failure,
+                Classification::RequiresEos(eos) => classify_eos = Some(eos),
+            }
+            response = Response::from_parts(head.into_parts().0, body);
+        } else if this.state.error_type_attribute
+            && (response.status().is_client_error() || response.status().is_server_error())
+        {
+            error_type = Some(response.status().as_str().to_string());
+        }
+        if let Some(error_type) = &error_type {
+            labels.push(KeyValue::new("error.type", error_type.clone()));
+        }
+        if this.state.detailed_attributes_on_error_only {
+            let failed = match classified {
+                true => error_type.is_some() || classify_eos.is_some(),
+                false => response.status().is_client_error() || response.status().is_server_error(),
+            };
+            if !failed {
+                labels.truncate(1);
+                labels.push(KeyValue::new("http.response.status_class", status_class(response.status())));
+                labels.extend(this.state.constant_labels.iter().cloned());
+            }
+        }
+
+        // the upgraded connections and the event streams outlive the response headers,
+        // see [HttpMetricsLayerBuilder::with_websocket_handling] and [HttpMetricsLayerBuilder::with_sse_handling]
+        let long_lived = if response.status() == http::StatusCode::SWITCHING_PROTOCOLS {
+            match this.state.websocket_mode {
+                WsMode::Skip => LongLived::Skip,
+                WsMode::Separate => LongLived::Upgrade,
+                WsMode::Record => LongLived::No,
+            }
+        } else if this.state.sse_mode != SseMode::Record && is_event_stream(response.headers()) {
+            match this.state.sse_mode {
+                SseMode::Skip => LongLived::Skip,
+                _ => LongLived::Stream,
+            }
+        } else {
+            LongLived::No
+        };
+        let skip_sizes = matches!(long_lived, LongLived::Skip | LongLived::Upgrade);
+        let skip_body_sizes = this.state.size_metrics_on_success_only && !response.status().is_success();
+
+        if let Some(res_ttfb) = this.state.metric.res_ttfb.as_ref().filter(|_| !skip_sizes) {
+            response.body_mut().ttfb_recorder = Some(ElapsedRecorder {
+                histogram: res_ttfb.clone(),
+                labels: labels.clone(),
+                start: *this.start,
+                per_second: this.state.duration_unit.per_second(),
+                instrument: "http.server.response.time_to_first_byte",
+                attribute_filter: this.state.attribute_filter.clone(),
+            });
+        }
+
+        if let Some(res_body_duration) = this.state.metric.res_body_duration.as_ref().filter(|_| !skip_sizes) {
+            // the start is reset once the first chunk is sent
+            response.body_mut().body_recorder = Some(ElapsedRecorder {
+                histogram: res_body_duration.clone(),
+                labels: labels.clone(),
+                start: Instant::now(),
+                per_second: this.state.duration_unit.per_second(),
+                instrument: "http.server.response.body.duration",
+                attribute_filter: this.state.attribute_filter.clone(),
+            });
+        }
+
+        if let Some(stream_duration) = this.state.metric.stream_duration.as_ref() {
+            if long_lived == LongLived::Stream {
+                response.body_mut().stream_recorder = Some(ElapsedRecorder {
+                    histogram: stream_duration.clone(),
+                    labels: labels.clone(),
+                    start: *this.start,
+                    per_second: this.state.duration_unit.per_second(),
+                    instrument: "http.server.stream.duration",
+                    attribute_filter: this.state.attribute_filter.clone(),
+                });
+            }
+        }
+
+        let mut res_size = None;
+        if let Some(histogram) = this
+            .state
+            .metric
+            .res_size
+            .as_ref()
+            .filter(|_| !skip_sizes && !skip_body_sizes)
+        {
+            res_size = response_size(&mut response, this.state.exact_response_size, || BodySizeRecorder {
+                histogram: histogram.clone(),
+                labels: labels.clone(),
+                size: 0,
+                instrument: "http.server.response.size",
+                attribute_filter: this.state.attribute_filter.clone(),
+            });
+        }
+
+        let record = ResponseRecord {
+            labels,
+            latency,
+            path: std::mem::take(this.path),
+            status: response.status(),
+            req_size: *this.req_size,
+            req_header_size: *this.req_header_size,
+            req_body_size: this.req_body_size.take(),
+            res_size,
+            constant_labels: this.state.constant_labels.clone(),
+            attribute_filter: this.state.attribute_filter.clone(),
+            cpu_time: this
+                .cpu_time
+                .map(|cpu_time| cpu_time.as_secs_f64() * this.state.duration_unit.per_second()),
+            long_lived,
+            classified,
+            error_type,
+            skip_body_sizes,
+        };
+
+        let grpc_trailers = this.state.grpc_status_attribute && grpc_status.is_none() && is_grpc(response.headers());
+        if grpc_trailers || classify_eos.is_some() {
+            // the status of a gRPC response is usually sent in the trailers
+            response.body_mut().pending_record = Some(PendingRecord {
+                record,
+                metric: this.state.metric.clone(),
+                error_counter: this.state.error_counter,
+                grpc_trailers,
+                classify_eos,
+            });
+        } else {
+            record.record(&this.state.metric, this.state.error_counter);
+        }
+
+        Ready(Ok(response))
+    }
+}
+
+/// the response size from the body's size hint, or `None` when the body is counted by the
+/// recorder instead, see [HttpMetricsLayerBuilder::with_exact_response_size]
+fn response_size<B: httpBody>(
+    response: &mut Response<ResponseBody<B>>,
+    exact: bool,
+    recorder: impl FnOnce() -> BodySizeRecorder,
+) -> Option<u64> {
+    if exact {
+        response.body_mut().size_recorder = Some(recorder());
+        return None;
+    }
+    // many handlers set the `Content-Length` header even if the body size is unknown
+    Some(
+        response
+            .body()
+            .size_hint()
+            .upper()
+            .or_else(|| content_length(response.headers()))
+            .unwrap_or(0),
+    )
+}
+
+/// records the counted body size into the histogram once the body is finished
+struct BodySizeRecorder {
+    histogram: Histogram<u64>,
+    labels: Vec<KeyValue>,
+    size: u64,
+    instrument: &'static str,
+    attribute_filter: Option<AttributeFilterFn>,
+}
+
+impl BodySizeRecorder {
+    fn record(self) {
+        let labels = filter_labels(&self.attribute_filter, self.instrument, &self.labels);
+        self.histogram.record(self.size, &labels);
+    }
+
+    /// records the request size now, or once the request body is finished when it is counted,
+    /// see [HttpMetricsLayerBuilder::with_exact_request_size]
+    fn record_request(self, req_body_size: Option<Arc<RequestBodySize>>) {
+        match req_body_size {
+            Some(req_body_size) => req_body_size.record_when_finished(self),
+            None => self.record(),
+        }
+    }
+}
+
+/// the metrics of a response, recorded once the attributes are complete
+struct ResponseRecord {
+    labels: Vec<KeyValue>,
+    latency: f64,
+    path: String,
+    status: http::StatusCode,
+    req_size: u64,
+    req_header_size: u64,
+    req_body_size: Option<Arc<RequestBodySize>>,
+    /// the approximate response size, `None` if counted by the [ResponseBody] or disabled
+    res_size: Option<u64>,
+    /// the constant labels, also added to the error counter
+    constant_labels: Vec<KeyValue>,
+    attribute_filter: Option<AttributeFilterFn>,
+    long_lived: LongLived,
+    /// the CPU time spent polling the response future, in the duration unit
+    cpu_time: Option<f64>,
+    /// whether the response is classified by [HttpMetricsLayerBuilder::with_classifier]
+    classified: bool,
+    /// the failure class of the classified response, `None` if it succeeded
+    error_type: Option<String>,
+    /// whether the request size is left out, see [HttpMetricsLayerBuilder::with_size_metrics_on_success_only]
+    skip_body_sizes: bool,
+}
+
+/// how the duration and the sizes of a response outliving its headers are recorded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LongLived {
+    /// a regular response
+    No,
+    /// only the counters are recorded
+    Skip,
+    /// the duration is recorded into the upgrade duration histogram, the sizes are skipped
+    Upgrade,
+    /// the duration is recorded into the stream duration histogram once the body is finished
+    Stream,
+}
+
+impl ResponseRecord {
+    fn record(self, metric: &Metric, error_counter: Option<ErrorKind>) {
+        let labels = self.labels;
+        let filter = &self.attribute_filter;
+        let skip_sizes = matches!(self.long_lived, LongLived::Skip | LongLived::Upgrade);
+        if self.long_lived == LongLived::Upgrade {
+            if let Some(upgrade_duration) = &metric.upgrade_duration {
+                upgrade_duration.record(self.latency, &filter_labels(filter, "http.server.upgrade.duration", &labels));
+            }
+        }
+
+        if let Some(req_size) = metric.req_size.as_ref().filter(|_| !skip_sizes && !self.skip_body_sizes) {
+            let recorder = BodySizeRecorder {
+                histogram: req_size.clone(),
+                labels: labels.clone(),
+                size: self.req_size,
+                instrument: "http.server.request.size",
+                attribute_filter: filter.clone(),
+            };
+            recorder.record_request(self.req_body_size);
+        }
+
+        if let Some(req_header_size) = metric.req_header_size.as_ref().filter(|_| !skip_sizes) {
+            let header_labels = filter_labels(filter, "http.server.request.header.size", &labels);
+            req_header_size.record(self.req_header_size, &header_labels);
+        }
+
+        let req_duration = metric.route_req_duration.get(self.path.as_str());
+        let req_duration = req_duration.or(metric.req_duration.as_ref());
+        if let Some(req_duration) = req_duration.filter(|_| self.long_lived == LongLived::No) {
+            req_duration.record(self.latency, &filter_labels(filter, "http.server.request.duration", &labels));
+        }
+
+        if let Some(handler_duration) = metric.handler_duration.as_ref().filter(|_| !skip_sizes) {
+            handler_duration.record(self.latency, &filter_labels(filter, "http.server.handler.duration", &labels));
+        }
+
+        if let (Some(req_cpu_time), Some(cpu_time)) = (&metric.req_cpu_time, self.cpu_time) {
+            req_cpu_time.record(cpu_time, &filter_labels(filter, "http.server.request.cpu_time", &labels));
+        }
+
+        if let Some(req_count) = &metric.req_count {
+            req_count.add(1, &filter_labels(filter, "http.server.request.count", &labels));
+        }
+
+        if let (Some(req_errors), Some(kind)) = (&metric.req_errors, error_counter) {
+            let is_error = match self.classified {
+                true => self.error_type.is_some(),
+                false => kind.is_error(self.status),
+            };
+            if is_error {
+                // http.request.method, the route attribute, http.response.status_code, error.type and the constant labels
+                let status_code = KeyValue::new("http.response.status_code", status_code_value(self.status));
+                let mut error_labels = vec![labels[0].clone(), labels[1].clone(), status_code];
+                if let Some(error_type) = self.error_type {
+                    error_labels.push(KeyValue::new("error.type", error_type));
+                }
+                error_labels.extend(self.constant_labels);
+                req_errors.add(1, &filter_labels(filter, "http.server.request.errors", &error_labels));
+            }
+        }
+
+        if let (Some(res_size), Some(size)) = (&metric.res_size, self.res_size) {
+            res_size.record(size, &filter_labels(filter, "http.server.response.size", &labels));
+        }
+    }
+}
+
+/// a [ResponseRecord] waiting for the `grpc-status` trailer, or for the end of the stream to be classified
+struct PendingRecord {
+    record: ResponseRecord,
+    metric: Arc<Metric>,
+    error_counter: Option<ErrorKind>,
+    /// whether the `rpc.grpc.status_code` attribute is taken from the trailers
+    grpc_trailers: bool,
+    classify_eos: Option<Box<dyn DynClassifyEos>>,
+}
+
+impl PendingRecord {
+    /// completes the attributes from the trailers, if any, and records
+    fn finish(mut self, trailers: Option<&http::HeaderMap>, size_recorder: Option<&mut BodySizeRecorder>) {
+        let mut labels = Vec::new();
+        if let Some(status) = trailers.filter(|_| self.grpc_trailers).and_then(grpc_status) {
+            labels.push(KeyValue::new("rpc.grpc.status_code", status));
+        }
+        if let Some(error_type) = self.classify_eos.take().and_then(|eos| eos.classify_eos(trailers)) {
+            labels.push(KeyValue::new("error.type", error_type.clone()));
+            self.record.error_type = Some(error_type);
+        }
+        if let Some(recorder) = size_recorder {
+            recorder.labels.extend(labels.iter().cloned());
+        }
+        self.record.labels.extend(labels);
+        self.record.record(&self.metric, self.error_counter);
+    }
+}
+
+/// records the elapsed time since the request started into the histogram,
+/// once the first chunk of the response body is sent, or once the body is finished
+struct ElapsedRecorder {
+    histogram: Histogram<f64>,
+    labels: Vec<KeyValue>,
+    start: Instant,
+    per_second: f64,
+    instrument: &'static str,
+    attribute_filter: Option<AttributeFilterFn>,
+}
+
+impl ElapsedRecorder {
+    fn record(self) {
+        let elapsed = self.start.elapsed().as_secs_f64() * self.per_second;
+        let labels = filter_labels(&self.attribute_filter, self.instrument, &self.labels);
+        self.histogram.record(elapsed, &labels);
+    }
+
+    /// records the body duration since the first chunk, zero for a body without any chunk
+    fn record_body(self, started: bool) {
+        let elapsed = match started {
+            true => self.start.elapsed().as_secs_f64() * self.per_second,
+            false => 0.0,
+        };
+        let labels = filter_labels(&self.attribute_filter, self.instrument, &self.labels);
+        self.histogram.record(elapsed, &labels);
+    }
+}
+
+/// the request body size shared between [RequestBody] and [ResponseFuture].
+///
+/// the request body may be finished before or after the response is ready,
+/// the size is recorded once both the body is finished and the labels are known.
+#[derive(Default)]
+struct RequestBodySize {
+    state: Mutex<RequestBodySizeState>,
+}
+
+#[derive(Default)]
+struct RequestBodySizeState {
+    size: u64,
+    finished: bool,
+    recorder: Option<BodySizeRecorder>,
+}
+
+impl RequestBodySize {
+    fn lock(&self) -> std::sync::MutexGuard<'_, RequestBodySizeState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn add(&self, size: u64) {
+        self.lock().size += size;
+    }
+
+    fn finish(&self) {
+        let mut state = self.lock();
+        if state.finished {
+            return;
+        }
+        state.finished = true;
+        if let Some(mut recorder) = state.recorder.take() {
+            recorder.size += state.size;
+            recorder.record();
+        }
+    }
+
+    fn record_when_finished(&self, mut recorder: BodySizeRecorder) {
+        let mut state = self.lock();
+        if state.finished {
+            recorder.size += state.size;
+            recorder.record();
+        } else {
+            state.recorder = Some(recorder);
+        }
+    }
+}
+
+pin_project! {
+    /// Request body passed to the inner service by [`HttpMetrics`].
+    ///
+    /// it counts the bytes actually read by the handler when [HttpMetricsLayerBuilder::with_exact_request_size]
+    /// is enabled, otherwise it is a transparent wrapper of the inner body.
+    pub struct RequestBody<B> {
+        #[pin]
+        inner: B,
+        size: Option<Arc<RequestBodySize>>,
+    }
+
+    impl<B> PinnedDrop for RequestBody<B> {
+        fn drop(this: Pin<&mut Self>) {
+            if let Some(size) = &this.size {
+                size.finish();
+            }
+        }
+    }
+}
+
+impl<B> RequestBody<B> {
+    fn new(inner: B) -> Self {
+        Self { inner, size: None }
+    }
+}
+
+impl<B: httpBody> httpBody for RequestBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        let frame = ready!(this.inner.poll_frame(cx));
+
+        if let Some(size) = this.size {
+            match &frame {
+                Some(Ok(frame)) => {
+                    if let Some(data) = frame.data_ref() {
+                        size.add(data.remaining() as u64);
+                    }
+                }
+                None => size.finish(),
+                Some(Err(_)) => {}
+            }
+        }
+
+        Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+pin_project! {
+    /// Response body for [`HttpMetrics`] Service.
+    ///
+    /// it counts the bytes actually sent when [HttpMetricsLayerBuilder::with_exact_response_size] is enabled,
+    /// and records the time to first byte when [HttpMetricsLayerBuilder::with_ttfb] is enabled,
+    /// otherwise it is a transparent wrapper of the inner body.
+    pub struct ResponseBody<B> {
+        #[pin]
+        inner: B,
+        size_recorder: Option<BodySizeRecorder>,
+        ttfb_recorder: Option<ElapsedRecorder>,
+        stream_recorder: Option<ElapsedRecorder>,
+        body_recorder: Option<ElapsedRecorder>,
+        // whether the first chunk of the body is sent
+        body_started: bool,
+        pending_record: Option<PendingRecord>,
+    }
+
+    impl<B> PinnedDrop for ResponseBody<B> {
+        fn drop(this: Pin<&mut Self>) {
+            // the body may be dropped before it is finished, e.g. the client disconnected
+            let this = this.project();
+            if let Some(pending) = this.pending_record.take() {
+                pending.record.record(&pending.metric, pending.error_counter);
+            }
+            if let Some(recorder) = this.size_recorder.take() {
+                recorder.record();
+            }
+            if let Some(recorder) = this.stream_recorder.take() {
+                recorder.record();
+            }
+            if let Some(recorder) = this.body_recorder.take() {
+                recorder.record_body(*this.body_started);
+            }
+        }
+    }
+}
+
+impl<B> ResponseBody<B> {
+    fn new(inner: B) -> Self {
+        ResponseBody {
+            inner,
+            size_recorder: None,
+            ttfb_recorder: None,
+            stream_recorder: None,
+            body_recorder: None,
+            body_started: false,
+            pending_record: None,
+        }
+    }
+}
+
+impl<B: httpBody> httpBody for ResponseBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        let frame = ready!(this.inner.poll_frame(cx));
+
+        match &frame {
+            Some(Ok(frame)) => {
+                if let (Some(recorder), Some(data)) = (this.size_recorder.as_mut(), frame.data_ref()) {
+                    recorder.size += data.remaining() as u64;
+                }
+                if frame.is_data() {
+                    if let Some(recorder) = this.ttfb_recorder.take() {
+                        recorder.record();
+                    }
+                    if !*this.body_started {
+                        *this.body_started = true;
+                        if let Some(recorder) = this.body_recorder.as_mut() {
+                            recorder.start = Instant::now();
+                        }
+                    }
+                }
+                if let Some(trailers) = frame.trailers_ref() {
+                    if let Some(pending) = this.pending_record.take() {
+                        pending.finish(Some(trailers), this.size_recorder.as_mut());
+                    }
+                }
+            }
+            None => {
+                if let Some(pending) = this.pending_record.take() {
+                    pending.finish(None, this.size_recorder.as_mut());
+                }
+                if let Some(recorder) = this.size_recorder.take() {
+                    recorder.record();
+                }
+                if let Some(recorder) = this.stream_recorder.take() {
+                    recorder.record();
+                }
+                if let Some(recorder) = this.body_recorder.take() {
+                    recorder.record_body(*this.body_started);
+                }
+            }
+            Some(Err(_)) => {}
+        }
+
+        Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// helpers to assert on the recorded data points without a prometheus exporter, enabled by the `testing` feature.
+///
+/// ```
+/// use axum::{routing::get, Router};
+/// use axum_otel_metrics::testing::{PointValue, TestRecorder};
+/// use axum_otel_metrics::HttpMetricsLayerBuilder;
+/// use tower::ServiceExt;
+///
+/// # tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(async {
+/// let recorder = TestRecorder::new();
+/// let app = Router::new()
+///     .route("/", get(|| async { "ok" }))
+///     .layer(recorder.layer(HttpMetricsLayerBuilder::new()));
+/// let req = axum::http::Request::get("/").body(axum::body::Body::empty()).unwrap();
+/// app.oneshot(req).await.unwrap();
+///
+/// let points = recorder.points("http.server.request.duration");
+/// assert_eq!(points[0].attribute("http.route"), Some(&"/".into()));
+/// assert!(matches!(points[0].value, PointValue::Histogram { count: 1, .. }));
+/// # });
+/// ```
+#[cfg(any(test, feature = "testing"))]
+pub mod testing {
+    use crate::{HttpMetricsLayer, HttpMetricsLayerBuilder, LayerReader};
+    use opentelemetry::{KeyValue, Value};
+    use opentelemetry_sdk::metrics::data::{self, ResourceMetrics};
+    use opentelemetry_sdk::metrics::reader::MetricReader;
+    use opentelemetry_sdk::metrics::{ManualReader, SdkMeterProvider};
+    use opentelemetry_sdk::Resource;
+    use std::sync::Arc;
+
+    /// a meter provider with an in-memory reader, the layers built by it record into the reader
+    #[derive(Clone, Debug)]
+    pub struct TestRecorder {
+        reader: Arc<ManualReader>,
+        provider: SdkMeterProvider,
+    }
+
+    impl Default for TestRecorder {
+        fn default() -> Self {
+            TestRecorder::new()
+        }
+    }
+
+    impl TestRecorder {
+        pub fn new() -> Self {
+            let reader = Arc::new(ManualReader::default());
+            let provider = SdkMeterProvider::builder()
+                .with_reader(LayerReader {
+                    inner: reader.clone(),
+                    temporality: None,
+                })
+                .build();
+            TestRecorder { reader, provider }
+        }
+
+        /// build the layer with the meter provider of the recorder
+        pub fn layer(&self, builder: HttpMetricsLayerBuilder) -> HttpMetricsLayer {
+            builder.with_provider(self.provider.clone()).build()
+        }
+
+        /// the meter provider of the recorder, e.g. for [HttpMetricsLayerBuilder::with_meter]
+        pub fn provider(&self) -> &SdkMeterProvider {
+            &self.provider
+        }
+
+        /// collect the data points of all the instruments
+        pub fn collect(&self) -> Vec<Point> {
+            let mut rm = ResourceMetrics {
+                resource: Resource::empty(),
+                scope_metrics: vec![],
+            };
+            self.reader.collect(&mut rm).expect("failed to collect the metrics");
+            rm.scope_metrics
+                .iter()
+                .flat_map(|scope| scope.metrics.iter())
+                .flat_map(|metric| {
+                    values(metric.data.as_any()).into_iter().map(|(attributes, value)| Point {
+                        instrument: metric.name.to_string(),
+                        attributes,
+                        value,
+                    })
+                })
+                .collect()
+        }
+
+        /// collect the data points of the given instrument, e.g. `http.server.request.duration`
+        pub fn points(&self, instrument: &str) -> Vec<Point> {
+            self.collect().into_iter().filter(|p| p.instrument == instrument).collect()
+        }
+    }
+
+    /// a recorded data point
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct Point {
+        pub instrument: String,
+        pub attributes: Vec<KeyValue>,
+        pub value: PointValue,
+    }
+
+    impl Point {
+        /// the value of the given attribute
+        pub fn attribute(&self, key: &str) -> Option<&Value> {
+            self.attributes.iter().find(|kv| kv.key.as_str() == key).map(|kv| &kv.value)
+        }
+    }
+
+    /// the value of a data point, the integer values are converted to `f64`
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum PointValue {
+        /// a counter or an up-down counter
+        Sum(f64),
+        Gauge(f64),
+        Histogram {
+            count: u64,
+            sum: f64,
+            bounds: Vec<f64>,
+            bucket_counts: Vec<u64>,
+        },
+    }
+
+    /// the attributes and the value of each data point of an aggregation
+    fn values(data: &dyn std::any::Any) -> Vec<(Vec<KeyValue>, PointValue)> {
+        fn points<T: Copy>(points: &[data::DataPoint<T>], value: impl Fn(T) -> PointValue) -> Vec<(Vec<KeyValue>, PointValue)> {
+            points.iter().map(|p| (p.attributes.clone(), value(p.value))).collect()
+        }
+        fn histogram<T: Copy>(
+            points: &[data::HistogramDataPoint<T>],
+            sum: impl Fn(T) -> f64,
+        ) -> Vec<(Vec<KeyValue>, PointValue)> {
+            points
+                .iter()
+                .map(|p| {
+                    let value = PointValue::Histogram {
+                        count: p.count,
+                        sum: sum(p.sum),
+                        bounds: p.bounds.clone(),
+                        bucket_counts: p.bucket_counts.clone(),
+                    };
+                    (p.attributes.clone(), value)
+                })
+                .collect()
+        }
+
+        if let Some(h) = data.downcast_ref::<data::Histogram<f64>>() {
+            histogram(&h.data_points, |v| v)
+        } else if let Some(h) = data.downcast_ref::<data::Histogram<u64>>() {
+            histogram(&h.data_points, |v| v as f64)
+        } else if let Some(s) = data.downcast_ref::<data::Sum<u64>>() {
+            points(&s.data_points, |v| PointValue::Sum(v as f64))
+        } else if let Some(s) = data.downcast_ref::<data::Sum<i64>>() {
+            points(&s.data_points, |v| PointValue::Sum(v as f64))
+        } else if let Some(s) = data.downcast_ref::<data::Sum<f64>>() {
+            points(&s.data_points, PointValue::Sum)
+        } else if let Some(g) = data.downcast_ref::<data::Gauge<i64>>() {
+            points(&g.data_points, |v| PointValue::Gauge(v as f64))
+        } else if let Some(g) = data.downcast_ref::<data::Gauge<f64>>() {
+            points(&g.data_points, PointValue::Gauge)
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::HttpMetricsLayer;
+    use crate::HttpMetricsLayerBuilder;
+    use axum::body::Body;
+    use axum::extract::State;
+    use axum::http::{Method, Request};
+    use axum::routing::get;
+    use axum::Router;
+    use opentelemetry::metrics::MeterProvider;
+    use opentelemetry::{global, Context, KeyValue};
+    use opentelemetry_sdk::metrics::reader::MetricReader;
+    use opentelemetry_sdk::metrics::{InstrumentKind, SdkMeterProvider, Temporality};
+    use prometheus::{Encoder, Registry, TextEncoder};
+    use std::borrow::Cow;
+    use std::sync::Arc;
+    use tower::{Layer, Service, ServiceExt};
+
+    /// a meter provider backed by its own prometheus registry,
+    /// so that tests do not interfere with each other through the global meter provider
+    struct TestMetrics {
+        registry: Registry,
+        provider: SdkMeterProvider,
+    }
+
+    impl TestMetrics {
+        fn new() -> Self {
+            let registry = Registry::new();
+            let exporter = opentelemetry_prometheus::exporter()
+                .with_registry(registry.clone())
+                .build()
+                .unwrap();
+            let provider = SdkMeterProvider::builder().with_reader(exporter).build();
+            TestMetrics { registry, provider }
+        }
+
+        fn layer(&self, builder: HttpMetricsLayerBuilder) -> HttpMetricsLayer {
+            builder.build_with_meter(self.provider.meter("test"))
+        }
+
+        fn gather(&self) -> String {
+            let mut buffer = Vec::new();
+            TextEncoder::new().encode(&self.registry.gather(), &mut buffer).unwrap();
+            String::from_utf8(buffer).unwrap()
+        }
+    }
+
+    /// a gRPC response body with the status in the trailers
+    struct GrpcBody {
+        data: Option<bytes::Bytes>,
+        trailers: Option<axum::http::HeaderMap>,
+    }
+
+    impl http_body::Body for GrpcBody {
+        type Data = bytes::Bytes;
+        type Error = std::convert::Infallible;
+
+        fn poll_frame(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Option<Result<http_body::Frame<Self::Data>, Self::Error>>> {
+            if let Some(data) = self.data.take() {
+                return std::task::Poll::Ready(Some(Ok(http_body::Frame::data(data))));
+            }
+            std::task::Poll::Ready(self.trailers.take().map(|t| Ok(http_body::Frame::trailers(t))))
+        }
+    }
+
+    /// a reader shared with the test, standing in for an OTLP periodic reader
+    #[derive(Clone, Debug)]
+    struct SharedReader(Arc<opentelemetry_sdk::metrics::ManualReader>);
+
+    impl MetricReader for SharedReader {
+        fn register_pipeline(&self, pipeline: std::sync::Weak<opentelemetry_sdk::metrics::Pipeline>) {
+            self.0.register_pipeline(pipeline)
+        }
+        fn collect(
+            &self,
+            rm: &mut opentelemetry_sdk::metrics::data::ResourceMetrics,
+        ) -> opentelemetry_sdk::metrics::MetricResult<()> {
+            self.0.collect(rm)
+        }
+        fn force_flush(&self) -> opentelemetry_sdk::metrics::MetricResult<()> {
+            self.0.force_flush()
+        }
+        fn shutdown(&self) -> opentelemetry_sdk::metrics::MetricResult<()> {
+            self.0.shutdown()
+        }
+        fn temporality(&self, kind: InstrumentKind) -> Temporality {
+            self.0.temporality(kind)
+        }
+    }
+
+    async fn send(app: &Router, req: Request<Body>) -> axum::response::Response {
+        app.clone().oneshot(req).await.unwrap()
+    }
+
+    async fn handler() -> &'static str {
+        "<h1>Hello, World!</h1>"
+    }
+
+    #[tokio::test]
+    async fn test_prometheus_exporter() {
+        let _cx = Context::current();
+
+        let registry = Registry::new();
+
+        // init prometheus exporter
+        let exporter = opentelemetry_prometheus::exporter()
+            .with_registry(registry.clone())
+            .build()
+            .unwrap();
+
+        let provider = SdkMeterProvider::builder().with_reader(exporter).build();
+
+        // init the global meter provider
+        global::set_meter_provider(provider.clone());
+
+        let meter = global::meter("my-app");
+
+        // Use two instruments
+        let counter = meter.u64_counter("a.counter").with_description("Counts things").build();
+        let recorder = meter.u64_histogram("a.histogram").with_description("Records values").build();
+
+        counter.add(100, &[KeyValue::new("key", "value")]);
+        recorder.record(100, &[KeyValue::new("key", "value")]);
+
+        // Encode data as text or protobuf
+        let encoder = TextEncoder::new();
+        let metric_families = registry.gather();
+        let mut result = Vec::new();
+        encoder.encode(&metric_families, &mut result).expect("encode failed");
+        println!("{}", String::from_utf8(result).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_prom_exporter_builder() {
+        let metrics = HttpMetricsLayerBuilder::new().build();
+        let _app = Router::<HttpMetricsLayer>::new()
+            // export metrics at `/metrics` endpoint
+            .route(
+                "/metrics",
+                get(|| async {
+                    let mut buffer = Vec::new();
+                    let encoder = TextEncoder::new();
+                    encoder.encode(&prometheus::gather(), &mut buffer).unwrap();
+                    // return metrics
+                    String::from_utf8(buffer).unwrap()
+                }),
+            )
+            .route("/", get(handler))
+            .route("/hello", get(handler))
+            .route("/world", get(handler))
+            // add the metrics middleware
+            .layer(metrics);
+
+        async fn handler() -> &'static str {
+            "<h1>Hello, World!</h1>"
+        }
+    }
+
+    #[tokio::test]
+    async fn test_builder_with_state_router() {
+        #[derive(Clone)]
+        struct AppState {}
+
+        let metrics = HttpMetricsLayerBuilder::new().build();
+        let _app: Router<AppState> = Router::new()
+            .route(
+                "/metrics",
+                get(|| async {
+                    let mut buffer = Vec::new();
+                    let encoder = TextEncoder::new();
+                    encoder.encode(&prometheus::gather(), &mut buffer).unwrap();
+                    // return metrics
+                    String::from_utf8(buffer).unwrap()
+                }),
+            )
+            .route("/", get(handler))
+            .route("/hello", get(handler))
+            .route("/world", get(handler))
+            // add the metrics middleware
+            .layer(metrics)
+            .with_state(AppState {});
+
+        async fn handler(_state: State<AppState>) -> &'static str {
+            "<h1>Hello, World!</h1>"
+        }
+    }
+
+    #[tokio::test]
+    async fn test_builder_with_arced_skipper() {
+        #[derive(Clone)]
+        struct AppState {}
+
+        let metrics = HttpMetricsLayerBuilder::new()
+            .with_skipper(crate::PathSkipper::new_with_fn(Arc::new(|_: &str| true)))
+            .build();
+        let _app: Router<AppState> = Router::new()
+            .route(
+                "/metrics",
+                get(|| async {
+                    let mut buffer = Vec::new();
+                    let encoder = TextEncoder::new();
+                    encoder.encode(&prometheus::gather(), &mut buffer).unwrap();
+                    // return metrics
+                    String::from_utf8(buffer).unwrap()
+                }),
+            )
+            .route("/", get(handler))
+            // add the metrics middleware
+            .layer(metrics)
+            .with_state(AppState {});
+
+        async fn handler(_state: State<AppState>) -> &'static str {
+            "<h1>Hello, World!</h1>"
+        }
+    }
+
+    #[tokio::test]
+    async fn test_with_is_tls() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_is_tls(true)));
+
+        let req = Request::get("/")
+            .header("X-Forwarded-Proto", "http")
+            .body(Body::empty())
+            .unwrap();
+        send(&app, req).await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"url_scheme="https""#), "{output}");
+        assert!(!output.contains(r#"url_scheme="http""#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_unmatched_route_label() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new()));
+        send(&app, Request::get("/not-found").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"http_route="__unmatched__""#), "{output}");
+
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_unmatched_route_label("unknown_route".to_string())));
+        send(&app, Request::get("/not-found").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"http_route="unknown_route""#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_with_attributes_from_request() {
+        let metrics = TestMetrics::new();
+        let app = Router::new().route("/", get(handler)).layer(metrics.layer(
+            HttpMetricsLayerBuilder::new().with_attributes_from_request(Arc::new(|parts| {
+                let tenant = parts
+                    .headers
+                    .get("X-Tenant-Id")
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or("none")
+                    .to_string();
+                vec![KeyValue::new("tenant", tenant)]
+            })),
+        ));
+        let req = Request::get("/").header("X-Tenant-Id", "acme").body(Body::empty()).unwrap();
+        send(&app, req).await;
+
+        let output = metrics.gather();
+        let line = output
+            .lines()
+            .find(|l| l.starts_with("http_server_request_duration_seconds_count"))
+            .unwrap();
+        assert!(line.contains(r#"tenant="acme""#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_with_attributes_from_response() {
+        async fn cached() -> impl axum::response::IntoResponse {
+            ([("X-Cache", "HIT")], "cached")
+        }
+
+        let metrics = TestMetrics::new();
+        let app = Router::new().route("/", get(cached)).layer(metrics.layer(
+            HttpMetricsLayerBuilder::new().with_attributes_from_response(Arc::new(|parts| {
+                let cache = parts
+                    .headers
+                    .get("X-Cache")
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or("NONE")
+                    .to_string();
+                vec![KeyValue::new("cache", cache)]
+            })),
+        ));
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        let line = output
+            .lines()
+            .find(|l| l.starts_with("http_server_request_duration_seconds_count"))
+            .unwrap();
+        assert!(line.contains(r#"cache="HIT""#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_status_class_attribute() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/unavailable", get(|| async { axum::http::StatusCode::SERVICE_UNAVAILABLE }))
+            .layer(
+                metrics.layer(
+                    HttpMetricsLayerBuilder::new()
+                        .with_status_class_attribute(true)
+                        .with_status_code_attribute(false),
+                ),
+            );
+        send(&app, Request::get("/unavailable").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"http_response_status_class="5xx""#), "{output}");
+        assert!(!output.contains("http_response_status_code"), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_exact_response_size() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route(
+                "/stream",
+                get(|| async {
+                    let chunks = vec![Ok::<_, std::convert::Infallible>("hello"), Ok(" world")];
+                    Body::from_stream(futures_util::stream::iter(chunks))
+                }),
+            )
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_exact_response_size(true)));
+        let res = send(&app, Request::get("/stream").body(Body::empty()).unwrap()).await;
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"hello world");
+
+        let output = metrics.gather();
+        let line = output
+            .lines()
+            .find(|l| l.starts_with("http_server_response_size_bytes_sum"))
+            .unwrap();
+        assert!(line.ends_with(" 11"), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_exact_request_size() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route(
+                "/upload",
+                axum::routing::post(|body: String| async move { body.len().to_string() }),
+            )
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_exact_request_size(true)));
+
+        // a chunked body without `Content-Length`
+        let chunks = vec![Ok::<_, std::convert::Infallible>("hello"), Ok(" world")];
+        let req = Request::post("/upload")
+            .body(Body::from_stream(futures_util::stream::iter(chunks)))
+            .unwrap();
+        send(&app, req).await;
+
+        let output = metrics.gather();
+        let line = output
+            .lines()
+            .find(|l| l.starts_with("http_server_request_size_bytes_sum"))
+            .unwrap();
+        // path (7) + method (4) + body (11)
+        assert!(line.ends_with(" 22"), "{output}");
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn test_path_skipper_from_patterns() {
+        let skipper = crate::PathSkipper::from_patterns(&["^/internal/.*", r"^/v\d+/health$"]).unwrap();
+        assert!(skipper.skips(&Method::GET, "/internal/debug"));
+        assert!(skipper.skips(&Method::GET, "/v2/health"));
+        assert!(!skipper.skips(&Method::GET, "/v2/health/deep"));
+        assert!(!skipper.skips(&Method::GET, "/api/users"));
+
+        assert!(crate::PathSkipper::from_patterns(&["/broken("]).is_err());
+    }
+
+    #[test]
+    fn test_path_skipper_allow_only() {
+        let skipper = crate::PathSkipper::allow_only(|path| path.starts_with("/api/") || path.starts_with("/metrics"));
+        assert!(!skipper.skips(&Method::GET, "/api/orders"));
+        assert!(skipper.skips(&Method::GET, "/static/app.js"));
+        // the default skipped paths are never recorded
+        assert!(skipper.skips(&Method::GET, "/metrics"));
+    }
+
+    #[tokio::test]
+    async fn test_path_skipper_with_method() {
+        let metrics = TestMetrics::new();
+        let skipper = crate::PathSkipper::new_with_method(Arc::new(|method: &Method, path: &str| {
+            method == Method::OPTIONS && path.starts_with("/api/")
+        }));
+        let app = Router::new()
+            .route("/api/items", get(handler).options(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_skipper(skipper)));
+        for method in [Method::GET, Method::OPTIONS] {
+            let req = Request::builder()
+                .method(method)
+                .uri("/api/items")
+                .body(Body::empty())
+                .unwrap();
+            send(&app, req).await;
+        }
+
+        let output = metrics.gather();
+        assert!(
+            output.contains(r#"http_request_method="GET",http_response_status_code="200",http_route="/api/items""#),
+            "{output}"
+        );
+        assert!(
+            !output.contains(r#"http_request_method="OPTIONS",http_response_status_code"#),
+            "{output}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_route_duration_buckets() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .route("/upload", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_route_duration_buckets("/upload", vec![1.0, 30.0, 60.0])));
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        send(&app, Request::get("/upload").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"http_server_request_duration_upload_seconds_bucket{http_request_method="GET",http_response_status_code="200",http_route="/upload""#), "{output}");
+        assert!(output.contains(r#"le="30"}"#), "{output}");
+        assert!(!output.contains(r#"http_server_request_duration_seconds_bucket{http_request_method="GET",http_response_status_code="200",http_route="/upload""#), "{output}");
+        assert!(output.contains(r#"http_server_request_duration_seconds_bucket{http_request_method="GET",http_response_status_code="200",http_route="/""#), "{output}");
+    }
+
+    #[test]
+    fn test_split_host_port() {
+        use crate::split_host_port;
+        assert_eq!(split_host_port("example.com"), ("example.com", None));
+        assert_eq!(split_host_port("example.com:8080"), ("example.com", Some(8080)));
+        assert_eq!(split_host_port("[::1]:8080"), ("::1", Some(8080)));
+        assert_eq!(split_host_port("[2001:db8::1]"), ("2001:db8::1", None));
+    }
+
+    #[tokio::test]
+    async fn test_server_port_attribute() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_server_port_attribute(true)));
+        send(
+            &app,
+            Request::get("/")
+                .header("Host", "localhost:3000")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        send(
+            &app,
+            Request::get("/").header("Host", "example.com").body(Body::empty()).unwrap(),
+        )
+        .await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"server_port="3000""#), "{output}");
+        assert!(output.contains(r#"server_port="80""#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_tls_connection_server_port() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_server_port_attribute(true)));
+        let req = Request::get("/")
+            .header("Host", "example.com")
+            .extension(crate::TlsConnection(true))
+            .body(Body::empty())
+            .unwrap();
+        send(&app, req).await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"server_port="443""#), "{output}");
+        assert!(!output.contains(r#"server_port="80""#), "{output}");
+        assert!(output.contains(r#"url_scheme="https""#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_protocol_version_attribute() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_protocol_version_attribute(true)));
+        let req = Request::get("/")
+            .version(axum::http::Version::HTTP_2)
+            .body(Body::empty())
+            .unwrap();
+        send(&app, req).await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"network_protocol_version="2""#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_user_agent_classifier() {
+        let metrics = TestMetrics::new();
+        let app = Router::new().route("/", get(handler)).layer(metrics.layer(
+            HttpMetricsLayerBuilder::new().with_user_agent_classifier(Arc::new(|ua: &str| {
+                if ua.contains("bot") {
+                    "bot".to_string()
+                } else if ua.is_empty() {
+                    "none".to_string()
+                } else {
+                    "other".to_string()
+                }
+            })),
+        ));
+        send(
+            &app,
+            Request::get("/")
+                .header("User-Agent", "googlebot/2.1")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"user_agent_category="bot""#), "{output}");
+        assert!(output.contains(r#"user_agent_category="none""#), "{output}");
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[tokio::test]
+    async fn test_layer_routes() {
+        let metrics = TestMetrics::new();
+        let layer = metrics.layer(HttpMetricsLayerBuilder::new().with_prometheus_registry(metrics.registry.clone()));
+        let app = Router::new().route("/", get(handler)).merge(layer.routes()).layer(layer);
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+
+        let res = send(&app, Request::get("/metrics").body(Body::empty()).unwrap()).await;
+        assert_eq!(res.status(), axum::http::StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "text/plain; version=0.0.4");
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let output = String::from_utf8(body.to_vec()).unwrap();
+        assert!(output.contains(r#"http_server_request_duration_seconds_count{http_request_method="GET",http_response_status_code="200",http_route="/""#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_metric_prefix() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_metric_prefix("myapp".to_string())));
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(
+            output.contains("myapp_http_server_request_duration_seconds_count"),
+            "{output}"
+        );
+        assert!(output.contains("myapp_http_server_active_requests"), "{output}");
+        assert!(!output.contains("\nhttp_server_request_duration_seconds_count"), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_duration_unit_milliseconds() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_duration_unit(crate::DurationUnit::Milliseconds)));
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(output.contains("http_server_request_duration_milliseconds_count"), "{output}");
+        // the default buckets are scaled to milliseconds
+        assert!(output.contains(r#"le="10000"}"#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_request_counter() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_request_counter(true)));
+        for _ in 0..3 {
+            send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        }
+
+        let output = metrics.gather();
+        let line = output
+            .lines()
+            .find(|l| l.starts_with("http_server_request_count_total{"))
+            .unwrap();
+        assert!(line.ends_with(" 3"), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_error_counter() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .route("/fail", get(|| async { axum::http::StatusCode::INTERNAL_SERVER_ERROR }))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_error_counter(crate::ErrorKind::ServerOnly)));
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        send(&app, Request::get("/fail").body(Body::empty()).unwrap()).await;
+        send(&app, Request::get("/not-found").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        let lines: Vec<_> = output
+            .lines()
+            .filter(|l| l.starts_with("http_server_request_errors_total{"))
+            .collect();
+        assert_eq!(lines.len(), 1, "{output}");
+        assert!(
+            lines[0].contains(r#"http_response_status_code="500",http_route="/fail""#),
+            "{output}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_failures() {
+        let metrics = TestMetrics::new();
+        let layer = metrics.layer(HttpMetricsLayerBuilder::new());
+        let service = layer.layer(tower::service_fn(|_req: Request<crate::RequestBody<Body>>| async {
+            Err::<axum::response::Response, _>("boom")
+        }));
+        let res = service.oneshot(Request::get("/").body(Body::empty()).unwrap()).await;
+        assert!(res.is_err());
+
+        let output = metrics.gather();
+        assert!(
+            output.contains(r#"http_server_request_failures_total{http_request_method="GET",http_route="__unmatched__""#),
+            "{output}"
+        );
+        let active = output
+            .lines()
+            .find(|l| l.starts_with("http_server_active_requests{"))
+            .unwrap();
+        assert!(active.ends_with(" 0"), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_active_requests_on_cancel() {
+        use futures_util::FutureExt;
+        use tower::Service;
+
+        let metrics = TestMetrics::new();
+        let layer = metrics.layer(HttpMetricsLayerBuilder::new());
+        let mut service = layer.layer(tower::service_fn(|_req: Request<crate::RequestBody<Body>>| {
+            std::future::pending::<Result<axum::response::Response, std::convert::Infallible>>()
+        }));
+        // poll the response future once, then drop it
+        let future = service.call(Request::get("/").body(Body::empty()).unwrap());
+        assert!(future.now_or_never().is_none());
+
+        let output = metrics.gather();
+        let active = output
+            .lines()
+            .find(|l| l.starts_with("http_server_active_requests{"))
+            .unwrap();
+        assert!(active.ends_with(" 0"), "{output}");
+    }
+
+    #[test]
+    fn test_method_value() {
+        use crate::method_value;
+        assert_eq!(method_value(&Method::GET).as_str(), "GET");
+        assert_eq!(method_value(&Method::from_bytes(b"PURGE").unwrap()).as_str(), "PURGE");
+    }
+
+    #[tokio::test]
+    async fn test_disable_metrics() {
+        let metrics = TestMetrics::new();
+        let app = Router::new().route("/", get(handler)).layer(
+            metrics.layer(
+                HttpMetricsLayerBuilder::new()
+                    .with_request_size(false)
+                    .with_response_size(false),
+            ),
+        );
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(output.contains("http_server_request_duration_seconds_count"), "{output}");
+        assert!(output.contains("http_server_active_requests"), "{output}");
+        assert!(!output.contains("http_server_request_size_bytes"), "{output}");
+        assert!(!output.contains("http_server_response_size_bytes"), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_client_address() {
+        let metrics = TestMetrics::new();
+        let app = Router::new().route("/", get(handler)).layer(
+            metrics.layer(
+                HttpMetricsLayerBuilder::new()
+                    .with_client_address(crate::ClientAddrSource::ForwardedFor)
+                    .with_client_address_normalizer(Arc::new(|addr: &str| match addr.rsplit_once('.') {
+                        Some((subnet, _)) => format!("{subnet}.0/24"),
+                        None => addr.to_string(),
+                    })),
+            ),
+        );
+        let req = Request::get("/")
+            .header("X-Forwarded-For", "203.0.113.7, 10.0.0.1")
+            .body(Body::empty())
+            .unwrap();
+        send(&app, req).await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"client_address="203.0.113.0/24""#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_meter_scope() {
+        let metrics = TestMetrics::new();
+        let layer = HttpMetricsLayerBuilder::new()
+            .with_meter_scope("my-service", Some("1.2.3".to_string()))
+            .build_with_provider(&metrics.provider);
+        let app = Router::new().route("/", get(handler)).layer(layer);
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"otel_scope_name="my-service""#), "{output}");
+        assert!(output.contains(r#"otel_scope_version="1.2.3""#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_metric_reader() {
+        let registry = Registry::new();
+        let exporter = opentelemetry_prometheus::exporter()
+            .with_registry(registry.clone())
+            .build()
+            .unwrap();
+        let layer = HttpMetricsLayerBuilder::new().with_metric_reader(exporter).build();
+        let app = Router::new().route("/", get(handler)).layer(layer);
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&registry.gather(), &mut buffer).unwrap();
+        let output = String::from_utf8(buffer).unwrap();
+        assert!(output.contains("http_server_request_duration_seconds_count"), "{output}");
+    }
+
+    #[test]
+    fn test_temporality() {
+        let reader = |temporality| crate::LayerReader {
+            inner: Arc::new(opentelemetry_sdk::metrics::ManualReader::default()),
+            temporality,
+        };
+        assert_eq!(reader(None).temporality(InstrumentKind::Histogram), Temporality::Cumulative);
+        assert_eq!(
+            reader(Some(Temporality::Delta)).temporality(InstrumentKind::Histogram),
+            Temporality::Delta
+        );
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[tokio::test]
+    async fn test_prometheus() {
+        let (layer, registry) = HttpMetricsLayerBuilder::prometheus();
+        let app = Router::new().route("/", get(handler)).merge(layer.routes()).layer(layer);
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&registry.gather(), &mut buffer).unwrap();
+        let output = String::from_utf8(buffer).unwrap();
+        assert!(output.contains("http_server_request_duration_seconds_count"), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_resource_attributes() {
+        let registry = Registry::new();
+        let exporter = opentelemetry_prometheus::exporter()
+            .with_registry(registry.clone())
+            .build()
+            .unwrap();
+        let layer = HttpMetricsLayerBuilder::new()
+            .with_metric_reader(exporter)
+            .with_resource_attributes(vec![
+                KeyValue::new("service.name", "my-service"),
+                KeyValue::new("deployment.environment", "test"),
+            ])
+            .build();
+        let app = Router::new().route("/", get(handler)).layer(layer);
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&registry.gather(), &mut buffer).unwrap();
+        let output = String::from_utf8(buffer).unwrap();
+        assert!(output.contains(r#"service_name="my-service""#), "{output}");
+        assert!(output.contains(r#"deployment_environment="test""#), "{output}");
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[tokio::test]
+    async fn test_self_observe() {
+        let metrics = TestMetrics::new();
+        let layer = metrics.layer(
+            HttpMetricsLayerBuilder::new()
+                .with_prometheus_registry(metrics.registry.clone())
+                .with_self_observe(true),
+        );
+        let app = Router::new().route("/", get(handler)).merge(layer.routes()).layer(layer);
+        send(&app, Request::get("/metrics").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(
+            output.contains(r#"http_server_scrape_duration_seconds_count{http_request_method="GET",http_route="/metrics""#),
+            "{output}"
+        );
+        assert!(!output.contains("http_server_request_duration_seconds_count"), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_ttfb() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_ttfb(true)));
+        let res = send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        assert!(!metrics.gather().contains("http_server_response_time_to_first_byte"));
+
+        axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let output = metrics.gather();
+        assert!(output.contains(r#"http_server_response_time_to_first_byte_seconds_count{http_request_method="GET",http_response_status_code="200",http_route="/""#), "{output}");
+        assert!(output.contains("http_server_request_duration_seconds_count"), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_server_address_attribute() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_server_address_attribute(false)));
+        send(
+            &app,
+            Request::get("/")
+                .header("Host", "tenant-1.example.com")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+
+        let output = metrics.gather();
+        assert!(output.contains("http_server_request_duration_seconds_count"), "{output}");
+        assert!(!output.contains("server_address"), "{output}");
+
+        let metrics = TestMetrics::new();
+        let app = Router::new().route("/", get(handler)).layer(metrics.layer(
+            HttpMetricsLayerBuilder::new().with_server_address_normalizer(Arc::new(|host: &str| {
+                if host.ends_with(".example.com") {
+                    "tenant.example.com".to_string()
+                } else {
+                    "other".to_string()
+                }
+            })),
+        ));
+        send(
+            &app,
+            Request::get("/")
+                .header("Host", "tenant-1.example.com")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"server_address="tenant.example.com""#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_route_normalizer() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/v1/users", get(handler))
+            .route("/v2/users", get(handler))
+            .layer(
+                metrics.layer(HttpMetricsLayerBuilder::new().with_route_normalizer(Arc::new(|route: &str| {
+                    match route.strip_prefix("/v1").or_else(|| route.strip_prefix("/v2")) {
+                        Some(rest) => Cow::Owned(format!("/{{version}}{rest}")),
+                        None => Cow::Borrowed(route),
+                    }
+                }))),
+            );
+        send(&app, Request::get("/v1/users").body(Body::empty()).unwrap()).await;
+        send(&app, Request::get("/v2/users").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"http_route="/{version}/users""#), "{output}");
+        assert!(!output.contains(r#"http_route="/v1/users""#), "{output}");
+        assert!(output.contains(r#"http_server_request_duration_seconds_count{http_request_method="GET",http_response_status_code="200",http_route="/{version}/users",server_address="unknown",otel_scope_name="test"} 2"#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_server_address() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_server_address_fallback("localhost".to_string())));
+        send(
+            &app,
+            Request::get("/")
+                .header("Host", "example.com:443")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        send(
+            &app,
+            Request::get("/")
+                .header("Host", "[2001:db8::1]:8080")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"server_address="example.com""#), "{output}");
+        assert!(output.contains(r#"server_address="2001:db8::1""#), "{output}");
+        assert!(output.contains(r#"server_address="localhost""#), "{output}");
+        assert!(!output.contains(r#"server_address="unknown""#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_scheme_header() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new()));
+        let req = Request::get("/")
+            .header(
+                "X-Forwarded-Proto",
+                axum::http::HeaderValue::from_bytes(b"\xffhttps").unwrap(),
+            )
+            .body(Body::empty())
+            .unwrap();
+        let res = send(&app, req).await;
+        assert_eq!(res.status(), axum::http::StatusCode::OK);
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"url_scheme="http""#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_ssl() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new()));
+        send(
+            &app,
+            Request::get("/").header("X-Forwarded-Ssl", "on").body(Body::empty()).unwrap(),
+        )
+        .await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"url_scheme="https""#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_trust_forwarded_headers() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_trust_forwarded_headers(false)));
+        send(
+            &app,
+            Request::get("/")
+                .header("X-Forwarded-Proto", "https")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"url_scheme="http""#), "{output}");
+        assert!(!output.contains(r#"url_scheme="https""#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_active_requests() {
+        let metrics = TestMetrics::new();
+        let layer = metrics.layer(HttpMetricsLayerBuilder::new());
+        let mut service = layer.layer(tower::service_fn(|_req: Request<crate::RequestBody<Body>>| async {
+            Ok::<_, std::convert::Infallible>(axum::response::Response::new(Body::empty()))
+        }));
+
+        let pending = service.call(Request::get("/").body(Body::empty()).unwrap());
+        assert_eq!(layer.active_requests(), 1);
+        drop(pending);
+        assert_eq!(layer.active_requests(), 0);
+
+        service.call(Request::get("/").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(layer.active_requests(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_route_attribute_mode() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/users/{id}", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_route_attribute_mode(crate::RouteMode::RawPath)));
+        send(&app, Request::get("/users/42").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"http_target="/users/42""#), "{output}");
+        assert!(!output.contains("http_route"), "{output}");
+
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/users/{id}", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_route_attribute_mode(crate::RouteMode::Both)));
+        send(&app, Request::get("/users/42").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(
+            output.contains(r#"http_route="/users/{id}",http_target="/users/42""#),
+            "{output}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_include_query_string() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/search", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_route_attribute_mode(crate::RouteMode::RawPath)));
+        send(&app, Request::get("/search?q=secret").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"http_target="/search""#), "{output}");
+        assert!(!output.contains("secret"), "{output}");
+
+        let metrics = TestMetrics::new();
+        let app = Router::new().route("/search", get(handler)).layer(
+            metrics.layer(
+                HttpMetricsLayerBuilder::new()
+                    .with_route_attribute_mode(crate::RouteMode::RawPath)
+                    .with_include_query_string(true),
+            ),
+        );
+        send(&app, Request::get("/search?q=rust").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"http_target="/search?q=rust""#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_content_type_attribute() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/json", axum::routing::post(|| async { axum::Json("ok") }))
+            .route("/", get(handler))
+            .layer(
+                metrics.layer(
+                    HttpMetricsLayerBuilder::new()
+                        .with_request_content_type_attribute(true)
+                        .with_response_content_type_attribute(true)
+                        .with_content_type_allow_list(vec!["application/json".to_string()]),
+                ),
+            );
+        let req = Request::post("/json")
+            .header("Content-Type", "application/json; charset=utf-8")
+            .body(Body::from("{}"))
+            .unwrap();
+        send(&app, req).await;
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"http_request_content_type="application/json""#), "{output}");
+        assert!(
+            output.contains(r#"http_response_content_type="application/json""#),
+            "{output}"
+        );
+        // text/plain; charset=utf-8 is not in the allow list
+        assert!(output.contains(r#"http_response_content_type="other""#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_grpc_status_attribute() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route(
+                "/trailers",
+                get(|| async {
+                    let mut trailers = axum::http::HeaderMap::new();
+                    trailers.insert("grpc-status", "5".parse().unwrap());
+                    let body = GrpcBody {
+                        data: Some(bytes::Bytes::from_static(b"hello")),
+                        trailers: Some(trailers),
+                    };
+                    ([("content-type", "application/grpc")], Body::new(body))
+                }),
+            )
+            .route(
+                "/headers",
+                get(|| async { [("content-type", "application/grpc"), ("grpc-status", "13")] }),
+            )
+            .route("/", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_grpc_status_attribute(true)));
+
+        let res = send(&app, Request::get("/trailers").body(Body::empty()).unwrap()).await;
+        assert!(!metrics.gather().contains(r#"http_route="/trailers""#));
+        axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        send(&app, Request::get("/headers").body(Body::empty()).unwrap()).await;
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(
+            output.contains(r#"http_route="/trailers",rpc_grpc_status_code="5""#),
+            "{output}"
+        );
+        assert!(
+            output.contains(r#"http_route="/headers",rpc_grpc_status_code="13""#),
+            "{output}"
+        );
+        assert!(output.contains(r#"http_route="/",server_address="unknown""#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_force_flush() {
+        let metrics = TestMetrics::new();
+        let layer = HttpMetricsLayerBuilder::new().with_provider(metrics.provider.clone()).build();
+        let app = Router::new().route("/", get(handler)).layer(layer.clone());
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        assert!(layer.force_flush().is_ok());
+        assert!(metrics.gather().contains("http_server_request_duration_seconds_count"));
+
+        assert!(HttpMetricsLayerBuilder::new().build().force_flush().is_ok());
+    }
+
+    #[test]
+    fn test_try_build_invalid_buckets() {
+        use crate::HttpMetricsError;
+        let err = HttpMetricsLayerBuilder::new().with_duration_buckets(vec![]).try_build().err();
+        assert!(matches!(err, Some(HttpMetricsError::InvalidBuckets)));
+        let err = HttpMetricsLayerBuilder::new()
+            .with_size_buckets(vec![10.0, 1.0])
+            .try_build()
+            .err();
+        assert!(matches!(err, Some(HttpMetricsError::InvalidBuckets)));
+        let err = HttpMetricsLayerBuilder::new()
+            .with_route_duration_buckets("/", vec![1.0, f64::NAN])
+            .try_build()
+            .err();
+        assert!(matches!(err, Some(HttpMetricsError::InvalidBuckets)));
+        assert!(HttpMetricsLayerBuilder::new()
+            .with_duration_buckets(vec![0.1, 1.0])
+            .with_size_buckets(vec![100.0, 1000.0])
+            .try_build()
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_custom_buckets() {
+        use crate::testing::{PointValue, TestRecorder};
+
+        let recorder = TestRecorder::new();
+        let app = Router::new().route("/", get(handler)).layer(
+            recorder.layer(
+                HttpMetricsLayerBuilder::new()
+                    .with_duration_buckets(vec![0.5, 1.0])
+                    .with_size_buckets(vec![]),
+            ),
+        );
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+
+        let duration = recorder.points("http.server.request.duration");
+        assert_eq!(duration.len(), 1, "{duration:?}");
+        assert_eq!(duration[0].attribute("http.route"), Some(&"/".into()));
+        assert_eq!(duration[0].attribute("http.response.status_code"), Some(&"200".into()));
+        match &duration[0].value {
+            PointValue::Histogram {
+                count,
+                bounds,
+                bucket_counts,
+                ..
+            } => {
+                assert_eq!(*count, 1);
+                assert_eq!(bounds, &[0.5, 1.0]);
+                assert_eq!(bucket_counts, &[1, 0, 0]);
+            }
+            value => panic!("not a histogram: {value:?}"),
+        }
+        // the invalid size buckets fall back to the default ones
+        let size = recorder.points("http.server.request.size");
+        assert!(
+            matches!(&size[0].value, PointValue::Histogram { bounds, .. } if bounds == &crate::default_size_buckets()),
+            "{size:?}"
+        );
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[tokio::test]
+    async fn test_dual_export() {
+        let registry = Registry::new();
+        let reader = SharedReader(Arc::new(opentelemetry_sdk::metrics::ManualReader::default()));
+        let layer = HttpMetricsLayerBuilder::new()
+            .with_dual_export(registry.clone(), reader.clone())
+            .build();
+        let app = Router::new().route("/", get(handler)).merge(layer.routes()).layer(layer);
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+
+        let res = send(&app, Request::get("/metrics").body(Body::empty()).unwrap()).await;
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let output = String::from_utf8(body.to_vec()).unwrap();
+        assert!(output.contains("http_server_request_duration_seconds_count"), "{output}");
+
+        let mut rm = opentelemetry_sdk::metrics::data::ResourceMetrics {
+            resource: opentelemetry_sdk::Resource::empty(),
+            scope_metrics: vec![],
+        };
+        reader.collect(&mut rm).unwrap();
+        let names: Vec<_> = rm
+            .scope_metrics
+            .iter()
+            .flat_map(|scope| scope.metrics.iter().map(|m| m.name.to_string()))
+            .collect();
+        assert!(names.contains(&"http.server.request.duration".to_string()), "{names:?}");
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn test_try_build_registry_error() {
+        let registry = Registry::new();
+        let exporter = opentelemetry_prometheus::exporter()
+            .with_registry(registry.clone())
+            .build()
+            .unwrap();
+        let _provider = SdkMeterProvider::builder().with_reader(exporter).build();
+
+        // the registry already has a prometheus exporter registered
+        let reader = opentelemetry_sdk::metrics::ManualReader::default();
+        let err = HttpMetricsLayerBuilder::new()
+            .with_dual_export(registry, reader)
+            .try_build()
+            .err();
+        assert!(matches!(err, Some(crate::HttpMetricsError::Registry(_))), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn test_client_port_attribute() {
+        let metrics = TestMetrics::new();
+        let app = Router::new().route("/", get(handler)).layer(
+            metrics.layer(
+                HttpMetricsLayerBuilder::new()
+                    .with_client_address(crate::ClientAddrSource::ConnectInfo)
+                    .with_client_port_attribute(true),
+            ),
+        );
+        let mut req = Request::get("/").body(Body::empty()).unwrap();
+        let addr: std::net::SocketAddr = "192.0.2.1:54321".parse().unwrap();
+        req.extensions_mut().insert(axum::extract::ConnectInfo(addr));
+        send(&app, req).await;
+        // without ConnectInfo, the attributes are omitted
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(
+            output.contains(r#"client_address="192.0.2.1",client_port="54321""#),
+            "{output}"
+        );
+        assert!(output.contains(r#"http_server_request_duration_seconds_count{http_request_method="GET",http_response_status_code="200",http_route="/",server_address="unknown""#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_sampling_ratio() {
+        let metrics = TestMetrics::new();
+        let layer = metrics.layer(HttpMetricsLayerBuilder::new().with_sampling_ratio(0.0));
+        let app = Router::new().route("/", get(handler)).layer(layer.clone());
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(!output.contains("http_server_request_duration_seconds"), "{output}");
+        assert!(output.contains("http_server_active_requests"), "{output}");
+        assert_eq!(layer.active_requests(), 0);
+
+        let samples = (0..10_000).filter(|_| crate::sample_random() < 0.25).count();
+        assert!((2_000..3_000).contains(&samples), "{samples}");
+    }
+
+    #[test]
+    fn test_default_buckets() {
+        let mut buckets = crate::default_duration_buckets();
+        assert_eq!(buckets.first(), Some(&0.0));
+        assert_eq!(buckets.last(), Some(&10.0));
+        buckets.extend([30.0, 60.0]);
+        assert!(HttpMetricsLayerBuilder::new()
+            .with_duration_buckets(buckets)
+            .try_build()
+            .is_ok());
+
+        let mut buckets = crate::default_size_buckets();
+        buckets.insert(0, 128.0);
+        assert!(HttpMetricsLayerBuilder::new().with_size_buckets(buckets).try_build().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_method_normalization() {
+        let foobar = Method::from_bytes(b"FOOBAR").unwrap();
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", axum::routing::any(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new()));
+        send(
+            &app,
+            Request::builder()
+                .method(foobar.clone())
+                .uri("/")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"http_request_method="_OTHER""#), "{output}");
+        assert!(!output.contains("FOOBAR"), "{output}");
+
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", axum::routing::any(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_method_normalization(false)));
+        send(&app, Request::builder().method(foobar).uri("/").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"http_request_method="FOOBAR""#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_header_size_metric() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_header_size_metric(true)));
+        send(
+            &app,
+            Request::get("/")
+                .header("x-large", "a".repeat(1000))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+
+        let output = metrics.gather();
+        // x-large: 7 + 1000 bytes
+        assert!(output.contains(r#"http_server_request_header_size_bytes_sum{http_request_method="GET",http_response_status_code="200",http_route="/",server_address="unknown",otel_scope_name="test"} 1007"#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_request_skipper() {
+        let metrics = TestMetrics::new();
+        let layer = metrics.layer(
+            HttpMetricsLayerBuilder::new().with_request_skipper(crate::RequestSkipper::new(Arc::new(
+                |parts: &axum::http::request::Parts| parts.headers.contains_key("X-Health-Probe"),
+            ))),
+        );
+        let mut service = layer.layer(tower::service_fn(|_req: Request<crate::RequestBody<Body>>| async {
+            Ok::<_, std::convert::Infallible>(axum::response::Response::new(Body::empty()))
+        }));
+
+        let pending = service.call(
+            Request::get("/")
+                .header("X-Health-Probe", "true")
+                .body(Body::empty())
+                .unwrap(),
+        );
+        assert_eq!(layer.active_requests(), 0);
+        pending.await.unwrap();
+
+        let output = metrics.gather();
+        assert!(!output.contains("http_server_active_requests"), "{output}");
+        assert!(!output.contains("http_server_request_duration_seconds"), "{output}");
+
+        service.call(Request::get("/").body(Body::empty()).unwrap()).await.unwrap();
+        let output = metrics.gather();
+        assert!(output.contains("http_server_request_duration_seconds"), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_skipped_path_not_active() {
+        let metrics = TestMetrics::new();
+        let layer = metrics.layer(HttpMetricsLayerBuilder::new());
+        let app = Router::new().route(
+            "/metrics",
+            get(|State(layer): State<HttpMetricsLayer>| async move { layer.active_requests().to_string() }),
+        );
+        let app = app.with_state(layer.clone()).layer(layer);
+
+        let res = send(&app, Request::get("/metrics").body(Body::empty()).unwrap()).await;
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "0");
+        assert!(!metrics.gather().contains("http_server_active_requests"));
+    }
+
+    #[tokio::test]
+    async fn test_active_requests_gauge_with_delta_temporality() {
+        fn active_requests(reader: &SharedReader) -> Vec<i64> {
+            let mut rm = opentelemetry_sdk::metrics::data::ResourceMetrics {
+                resource: opentelemetry_sdk::Resource::empty(),
+                scope_metrics: vec![],
+            };
+            reader.collect(&mut rm).unwrap();
+            rm.scope_metrics
+                .iter()
+                .flat_map(|scope| scope.metrics.iter())
+                .filter(|m| m.name == "http.server.active_requests")
+                .filter_map(|m| m.data.as_any().downcast_ref::<opentelemetry_sdk::metrics::data::Gauge<i64>>())
+                .flat_map(|gauge| gauge.data_points.iter().map(|dp| dp.value))
+                .collect()
+        }
+
+        let reader = SharedReader(Arc::new(opentelemetry_sdk::metrics::ManualReader::default()));
+        let layer = HttpMetricsLayerBuilder::new()
+            .with_metric_reader(reader.clone())
+            .with_temporality(Temporality::Delta)
+            .with_active_requests_mode(crate::ActiveRequestsMode::Gauge)
+            .build();
+        assert!(layer.state.metric.req_active.is_none());
+
+        let app = Router::new().route("/", get(handler)).layer(layer.clone());
+        // the router calls the layers lazily, wrap it to start the request right away
+        let mut svc = layer.layer(app.clone());
+        let pending = svc.call(Request::get("/").body(Body::empty()).unwrap());
+        assert_eq!(active_requests(&reader), vec![1]);
+        // the gauge keeps reporting the current value, not the change since the last collection
+        assert_eq!(active_requests(&reader), vec![1]);
+
+        drop(pending);
+        assert_eq!(active_requests(&reader), vec![0]);
+
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        assert_eq!(active_requests(&reader), vec![0]);
+    }
+
+    #[tokio::test]
+    async fn test_active_requests_route_label() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/users/{id}", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_active_requests_route_label(true)));
+        send(&app, Request::get("/users/1").body(Body::empty()).unwrap()).await;
+        send(&app, Request::get("/missing").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(
+            output.contains(
+                r#"http_server_active_requests{http_request_method="GET",http_route="/users/{id}",url_scheme="http""#
+            ),
+            "{output}"
+        );
+        // the unmatched requests don't get the route attribute
+        assert!(
+            output.contains(r#"http_server_active_requests{http_request_method="GET",url_scheme="http""#),
+            "{output}"
+        );
+        // the counter balances to zero for each attribute set
+        assert!(
+            output
+                .lines()
+                .filter(|line| line.starts_with("http_server_active_requests{"))
+                .all(|line| line.ends_with(" 0")),
+            "{output}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_metric_handles_extractor() {
+        async fn handler(metrics: crate::MetricHandles) -> &'static str {
+            metrics.req_failures.add(1, &[KeyValue::new("http.route", "/custom")]);
+            "ok"
+        }
+
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_metric_handles_extension(true)));
+        let res = send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        assert_eq!(res.status(), http::StatusCode::OK);
+
+        let output = metrics.gather();
+        assert!(
+            output.contains(r#"http_server_request_failures_total{http_route="/custom""#),
+            "{output}"
+        );
+
+        // the extension is not inserted by default
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new()));
+        let res = send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        assert_eq!(res.status(), http::StatusCode::INTERNAL_SERVER_ERROR);
+
+        // without the layer the extractor is rejected
+        let app = Router::new().route("/", get(handler));
+        let res = send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        assert_eq!(res.status(), http::StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_constant_labels() {
+        let metrics = TestMetrics::new();
+        let app = Router::new().route("/", get(handler)).layer(
+            metrics.layer(
+                HttpMetricsLayerBuilder::new()
+                    .with_constant_labels(vec![KeyValue::new("env", "dev")])
+                    .with_error_counter(crate::ErrorKind::ClientAndServer),
+            ),
+        );
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        send(&app, Request::get("/missing").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(
+            output.contains(r#"http_server_request_duration_seconds_count{env="dev",http_request_method="GET""#),
+            "{output}"
+        );
+        assert!(
+            output.contains(r#"http_server_active_requests{env="dev",http_request_method="GET""#),
+            "{output}"
+        );
+        assert!(
+            output.contains(r#"http_server_response_size_bytes_count{env="dev""#),
+            "{output}"
+        );
+        assert!(
+            output.contains(r#"http_server_request_errors_total{env="dev",http_request_method="GET""#),
+            "{output}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_response_size_from_content_length() {
+        async fn sized() -> &'static str {
+            "hello"
+        }
+
+        async fn streamed() -> axum::response::Response {
+            let stream =
+                futures_util::stream::once(async { Ok::<_, std::io::Error>(bytes::Bytes::from_static(b"hello world")) });
+            axum::response::Response::builder()
+                .header(http::header::CONTENT_LENGTH, "11")
+                .body(Body::from_stream(stream))
+                .unwrap()
+        }
+
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/sized", get(sized))
+            .route("/streamed", get(streamed))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new()));
+        send(&app, Request::get("/sized").body(Body::empty()).unwrap()).await;
+        send(&app, Request::get("/streamed").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        let sum = |route: &str| {
+            output
+                .lines()
+                .find(|line| {
+                    line.starts_with("http_server_response_size_bytes_sum")
+                        && line.contains(&format!(r#"http_route="{route}""#))
+                })
+                .and_then(|line| line.rsplit(' ').next())
+                .map(str::to_string)
+        };
+        assert_eq!(sum("/sized").as_deref(), Some("5"), "{output}");
+        assert_eq!(sum("/streamed").as_deref(), Some("11"), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_utilization() {
+        fn utilization(reader: &SharedReader) -> Vec<f64> {
+            let mut rm = opentelemetry_sdk::metrics::data::ResourceMetrics {
+                resource: opentelemetry_sdk::Resource::empty(),
+                scope_metrics: vec![],
+            };
+            reader.collect(&mut rm).unwrap();
+            rm.scope_metrics
+                .iter()
+                .flat_map(|scope| scope.metrics.iter())
+                .filter(|m| m.name == "http.server.concurrency.utilization")
+                .filter_map(|m| m.data.as_any().downcast_ref::<opentelemetry_sdk::metrics::data::Gauge<f64>>())
+                .flat_map(|gauge| gauge.data_points.iter().map(|dp| dp.value))
+                .collect()
+        }
+
+        let reader = SharedReader(Arc::new(opentelemetry_sdk::metrics::ManualReader::default()));
+        let layer = HttpMetricsLayerBuilder::new()
+            .with_metric_reader(reader.clone())
+            .with_concurrency_limit(4)
+            .build();
+        let app = Router::new().route("/", get(handler));
+        let mut svc = layer.layer(app);
+        let first = svc.call(Request::get("/").body(Body::empty()).unwrap());
+        let second = svc.call(Request::get("/").body(Body::empty()).unwrap());
+        assert_eq!(utilization(&reader), vec![0.5]);
+
+        drop(first);
+        drop(second);
+        assert_eq!(utilization(&reader), vec![0.0]);
+    }
+
+    #[tokio::test]
+    async fn test_tower_http_stack() {
+        use tower::ServiceBuilder;
+        use tower_http::trace::TraceLayer;
+
+        let metrics = TestMetrics::new();
+        let layer = metrics.layer(HttpMetricsLayerBuilder::new());
+        let router = Router::new().route("/", get(handler));
+
+        // beneath the trace layer
+        let svc = ServiceBuilder::new()
+            .layer(TraceLayer::new_for_http())
+            .layer(layer.clone())
+            .service(router.clone());
+        let res = svc.oneshot(Request::get("/").body(Body::empty()).unwrap()).await.unwrap();
+        axum::body::to_bytes(Body::new(res.into_body()), usize::MAX).await.unwrap();
+
+        // above the trace layer, which changes the response body type
+        let svc = ServiceBuilder::new()
+            .layer(layer)
+            .layer(TraceLayer::new_for_http())
+            .service(router);
+        let res = svc.oneshot(Request::get("/").body(Body::empty()).unwrap()).await.unwrap();
+        axum::body::to_bytes(Body::new(res.into_body()), usize::MAX).await.unwrap();
+
+        // outside of the router, the `MatchedPath` is not known yet
+        let output = metrics.gather();
+        assert!(
+            output.contains(r#"http_server_request_duration_seconds_count{http_request_method="GET",http_response_status_code="200",http_route="__unmatched__",server_address="unknown",otel_scope_name="test"} 2"#),
+            "{output}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_handle() {
+        let reader = SharedReader(Arc::new(opentelemetry_sdk::metrics::ManualReader::default()));
+        let layer = HttpMetricsLayerBuilder::new().with_metric_reader(reader.clone()).build();
+        let shutdown = layer.shutdown_handle();
+        let app = Router::new().route("/", get(handler)).layer(layer);
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+
+        shutdown.flush().unwrap();
+        shutdown.shutdown().unwrap();
+        // the reader is shut down along with the provider
+        let mut rm = opentelemetry_sdk::metrics::data::ResourceMetrics {
+            resource: opentelemetry_sdk::Resource::empty(),
+            scope_metrics: vec![],
+        };
+        assert!(reader.collect(&mut rm).is_err());
+
+        // a no-op for the global meter provider
+        let shutdown = HttpMetricsLayerBuilder::new().build().shutdown_handle();
+        shutdown.flush().unwrap();
+        shutdown.shutdown().unwrap();
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[tokio::test]
+    async fn test_metrics_format() {
+        let registry = Registry::new();
+        let layer = HttpMetricsLayerBuilder::new()
+            .with_prometheus_registry(registry.clone())
+            .build();
+        let counter = prometheus::IntCounter::new("test_counter", "a test counter").unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        counter.inc();
+        let app: Router = layer.routes();
+
+        let res = send(&app, Request::get("/metrics").body(Body::empty()).unwrap()).await;
+        assert_eq!(res.headers()[http::header::CONTENT_TYPE], prometheus::TEXT_FORMAT);
+
+        // the Accept header sent by prometheus when the protobuf scrape is enabled
+        let accept = "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited;q=0.7,text/plain;version=0.0.4;q=0.3,*/*;q=0.1";
+        let res = send(
+            &app,
+            Request::get("/metrics")
+                .header(http::header::ACCEPT, accept)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(res.headers()[http::header::CONTENT_TYPE], prometheus::PROTOBUF_FORMAT);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert!(body.windows(b"test_counter".len()).any(|w| w == b"test_counter"));
+
+        // the forced text format ignores the Accept header
+        let app: Router = HttpMetricsLayerBuilder::new()
+            .with_prometheus_registry(registry)
+            .with_metrics_format(crate::MetricsFormat::Text)
+            .build()
+            .routes();
+        let res = send(
+            &app,
+            Request::get("/metrics")
+                .header(http::header::ACCEPT, accept)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(res.headers()[http::header::CONTENT_TYPE], prometheus::TEXT_FORMAT);
+    }
+
+    #[tokio::test]
+    async fn test_slo() {
+        use std::time::Duration;
+
+        async fn slow() -> &'static str {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            "slow"
+        }
+
+        async fn failing() -> http::StatusCode {
+            http::StatusCode::INTERNAL_SERVER_ERROR
+        }
+
+        let metrics = TestMetrics::new();
+        let good = vec![crate::StatusClass::Success, crate::StatusClass::Redirection];
+        let app = Router::new()
+            .route("/", get(handler))
+            .route("/slow", get(slow))
+            .route("/failing", get(failing))
+            .route("/untracked", get(handler))
+            .layer(
+                metrics.layer(
+                    HttpMetricsLayerBuilder::new()
+                        .with_slo("/", Duration::from_secs(10), good.clone())
+                        .with_slo("/slow", Duration::from_millis(1), good.clone())
+                        .with_slo("/failing", Duration::from_secs(10), good),
+                ),
+            );
+        for path in ["/", "/", "/slow", "/failing", "/untracked"] {
+            send(&app, Request::get(path).body(Body::empty()).unwrap()).await;
+        }
+
+        let output = metrics.gather();
+        assert!(
+            output.contains(r#"http_server_slo_good_total{http_request_method="GET",http_route="/",otel_scope_name="test"} 2"#),
+            "{output}"
+        );
+        assert!(
+            output.contains(
+                r#"http_server_slo_bad_total{http_request_method="GET",http_route="/slow",otel_scope_name="test"} 1"#
+            ),
+            "{output}"
+        );
+        assert!(
+            output.contains(
+                r#"http_server_slo_bad_total{http_request_method="GET",http_route="/failing",otel_scope_name="test"} 1"#
+            ),
+            "{output}"
+        );
+        assert!(
+            !output.contains(r#"http_route="/untracked",otel_scope_name="test"} 1"#),
+            "{output}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connection_reused_attribute() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_connection_reused_attribute(true)));
+        let req = Request::get("/")
+            .extension(crate::ConnectionReused(true))
+            .body(Body::empty())
+            .unwrap();
+        send(&app, req).await;
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(
+            output.contains(
+                r#"http_server_request_duration_seconds_count{http_connection_reused="true",http_request_method="GET""#
+            ),
+            "{output}"
+        );
+        // the attribute is omitted without the extension
+        assert!(
+            output.contains(r#"http_server_request_duration_seconds_count{http_request_method="GET""#),
+            "{output}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_attribute_filter() {
+        let metrics = TestMetrics::new();
+        let filter: crate::AttributeFilterFn =
+            Arc::new(|instrument: &str, kv: &KeyValue| !(instrument.ends_with(".size") && kv.key.as_str() == "server.address"));
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_attribute_filter(filter)));
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        let series = |name: &str| {
+            output
+                .lines()
+                .find(|line| line.starts_with(name))
+                .unwrap_or_else(|| panic!("{name} not found in {output}"))
+                .to_string()
+        };
+        assert!(series("http_server_request_duration_seconds_count").contains("server_address"));
+        assert!(!series("http_server_request_size_bytes_count").contains("server_address"));
+        assert!(!series("http_server_response_size_bytes_count").contains("server_address"));
+        assert!(series("http_server_response_size_bytes_count").contains("http_route"));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_handling() {
+        async fn upgrade() -> http::StatusCode {
+            http::StatusCode::SWITCHING_PROTOCOLS
+        }
+
+        for (mode, duration, upgrade_duration) in [
+            (crate::WsMode::Skip, false, false),
+            (crate::WsMode::Separate, false, true),
+            (crate::WsMode::Record, true, false),
+        ] {
+            let metrics = TestMetrics::new();
+            let app = Router::new().route("/ws", get(upgrade)).layer(
+                metrics.layer(
+                    HttpMetricsLayerBuilder::new()
+                        .with_websocket_handling(mode)
+                        .with_request_counter(true),
+                ),
+            );
+            send(&app, Request::get("/ws").body(Body::empty()).unwrap()).await;
+
+            let output = metrics.gather();
+            assert_eq!(
+                output.contains("http_server_request_duration_seconds_count{"),
+                duration,
+                "{mode:?} {output}"
+            );
+            assert_eq!(
+                output.contains("http_server_response_size_bytes_count{"),
+                duration,
+                "{mode:?} {output}"
+            );
+            assert_eq!(
+                output.contains("http_server_upgrade_duration_seconds_count{"),
+                upgrade_duration,
+                "{mode:?} {output}"
+            );
+            assert!(output.contains("http_server_request_count_total{"), "{mode:?} {output}");
+        }
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[tokio::test]
+    async fn test_default_registry_metrics_handler() {
+        let app = Router::new().route("/metrics", get(crate::default_registry_metrics_handler));
+        let res = send(&app, Request::get("/metrics").body(Body::empty()).unwrap()).await;
+        assert_eq!(res.status(), http::StatusCode::OK);
+        assert_eq!(res.headers()[http::header::CONTENT_TYPE], "text/plain; version=0.0.4");
+    }
+
+    #[tokio::test]
+    async fn test_instrument_metadata() {
+        let metrics = TestMetrics::new();
+        let metadata = crate::InstrumentMetadata::new()
+            .with_description("http.server.request.duration", "Durée des requêtes HTTP.")
+            .with_unit("http.server.request.count", "{request}")
+            .with_description("http.server.request.count", "Nombre de requêtes HTTP.");
+        let app = Router::new().route("/", get(handler)).layer(
+            metrics.layer(
+                HttpMetricsLayerBuilder::new()
+                    .with_instrument_metadata(metadata)
+                    .with_request_counter(true),
+            ),
+        );
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(
+            output.contains("# HELP http_server_request_duration_seconds Durée des requêtes HTTP."),
+            "{output}"
+        );
+        assert!(
+            output.contains("# HELP http_server_request_count_total Nombre de requêtes HTTP."),
+            "{output}"
+        );
+        // the defaults are kept for the other instruments
+        assert!(
+            output.contains("# HELP http_server_request_size_bytes The HTTP request sizes in bytes."),
+            "{output}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resend_count_header() {
+        let metrics = TestMetrics::new();
+        let app =
+            Router::new().route("/", get(handler)).layer(metrics.layer(
+                HttpMetricsLayerBuilder::new().with_resend_count_header(http::HeaderName::from_static("x-retry-count")),
+            ));
+        for count in [None, Some("1"), Some("7"), Some("invalid")] {
+            let mut req = Request::get("/");
+            if let Some(count) = count {
+                req = req.header("X-Retry-Count", count);
+            }
+            send(&app, req.body(Body::empty()).unwrap()).await;
+        }
+
+        let output = metrics.gather();
+        let count = |bucket: &str| {
+            output
+                .lines()
+                .find(|line| {
+                    line.starts_with("http_server_request_duration_seconds_count")
+                        && line.contains(&format!(r#"http_request_resend_count="{bucket}""#))
+                })
+                .and_then(|line| line.rsplit(' ').next())
+                .map(str::to_string)
+        };
+        assert_eq!(count("0").as_deref(), Some("2"), "{output}");
+        assert_eq!(count("1").as_deref(), Some("1"), "{output}");
+        assert_eq!(count("3+").as_deref(), Some("1"), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_active_requests_balance() {
+        let metrics = TestMetrics::new();
+        let layer = metrics.layer(
+            HttpMetricsLayerBuilder::new()
+                .with_trust_forwarded_headers(true)
+                .with_active_requests_route_label(true)
+                .with_constant_labels(vec![KeyValue::new("env", "test")]),
+        );
+        let app = Router::new()
+            .route("/users/{id}", get(handler).post(handler))
+            .layer(layer.clone());
+
+        for i in 0..20 {
+            let method = if i % 2 == 0 { "GET" } else { "POST" };
+            let uri = if i % 3 == 0 { "/missing" } else { "/users/1" };
+            let mut req = Request::builder().method(method).uri(uri);
+            if i % 4 == 0 {
+                req = req.header("X-Forwarded-Proto", "https");
+            }
+            send(&app, req.body(Body::empty()).unwrap()).await;
+        }
+        // a cancelled request is decremented with the same attributes on drop
+        let mut svc = layer.layer(app.clone());
+        drop(svc.call(Request::get("/users/1").body(Body::empty()).unwrap()));
+
+        assert_eq!(layer.active_requests(), 0);
+        let output = metrics.gather();
+        let series: Vec<_> = output
+            .lines()
+            .filter(|line| line.starts_with("http_server_active_requests{"))
+            .collect();
+        assert!(series.len() > 1, "{output}");
+        assert!(series.iter().all(|line| line.ends_with(" 0")), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_sse_handling() {
+        async fn events() -> axum::response::Response {
+            let events = ["data: 1\n\n", "data: 2\n\n"].map(|event| Ok::<_, std::io::Error>(event.as_bytes()));
+            let stream = futures_util::stream::iter(events);
+            axum::response::Response::builder()
+                .header(http::header::CONTENT_TYPE, "text/event-stream")
+                .body(Body::from_stream(stream))
+                .unwrap()
+        }
+
+        for (mode, duration, size, stream_duration) in [
+            (crate::SseMode::Record, true, true, false),
+            (crate::SseMode::Skip, false, false, false),
+            (crate::SseMode::Stream, false, true, true),
+        ] {
+            let metrics = TestMetrics::new();
+            let app = Router::new()
+                .route("/events", get(events))
+                .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_sse_handling(mode)));
+            let res = send(&app, Request::get("/events").body(Body::empty()).unwrap()).await;
+            let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(body.len(), 18);
+
+            let output = metrics.gather();
+            assert_eq!(
+                output.contains("http_server_request_duration_seconds_count{"),
+                duration,
+                "{mode:?} {output}"
+            );
+            assert_eq!(
+                output.contains("http_server_response_size_bytes_count{"),
+                size,
+                "{mode:?} {output}"
+            );
+            assert_eq!(
+                output.contains("http_server_stream_duration_seconds_count{"),
+                stream_duration,
+                "{mode:?} {output}"
+            );
+        }
+    }
+
+    #[cfg(all(feature = "cpu-time", unix))]
+    #[tokio::test]
+    async fn test_cpu_time_metric() {
+        async fn busy() -> String {
+            (0..100_000u64).map(|i| i.wrapping_mul(i)).sum::<u64>().to_string()
+        }
+
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/busy", get(busy))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_cpu_time_metric(true)));
+        send(&app, Request::get("/busy").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(
+            output.contains(r#"http_server_request_cpu_time_seconds_count{http_request_method="GET""#),
+            "{output}"
+        );
+
+        // nothing is recorded when the metric is disabled
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/busy", get(busy))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_cpu_time_metric(false)));
+        send(&app, Request::get("/busy").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(output.contains("http_server_request_duration_seconds_count"), "{output}");
+        assert!(!output.contains("http_server_request_cpu_time"), "{output}");
+    }
+
+    #[test]
+    fn test_builder_getters() {
+        let builder = HttpMetricsLayerBuilder::new();
+        assert_eq!(builder.duration_buckets(), None);
+        assert_eq!(builder.size_buckets(), None);
+        assert!(builder.would_skip("/metrics"));
+        assert!(!builder.would_skip("/users"));
+
+        let builder = HttpMetricsLayerBuilder::new()
+            .with_duration_buckets(vec![0.1, 1.0])
+            .with_size_buckets(vec![1024.0])
+            .with_skipper(crate::PathSkipper::new(|path| path.starts_with("/internal")));
+        assert_eq!(builder.duration_buckets(), Some(&[0.1, 1.0][..]));
+        assert_eq!(builder.size_buckets(), Some(&[1024.0][..]));
+        assert!(builder.would_skip("/internal/health"));
+        assert!(!builder.would_skip("/metrics"));
+    }
+
+    #[test]
+    fn test_debug() {
+        let builder = HttpMetricsLayerBuilder::new()
+            .with_duration_buckets(vec![0.5, 1.0])
+            .with_attributes_from_request(Arc::new(|_| vec![]));
+        let output = format!("{builder:?}");
+        assert!(output.contains("duration_buckets: Some([0.5, 1.0])"), "{output}");
+        assert!(output.contains("skipper: <skipper fn>"), "{output}");
+        assert!(output.contains(r#"attributes_from_request: Some("<fn>")"#), "{output}");
+
+        let metrics = TestMetrics::new();
+        let output = format!("{:?}", metrics.layer(builder));
+        assert!(
+            output.starts_with("HttpMetricsLayer { metric: Metric { req_duration: Some("),
+            "{output}"
+        );
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[tokio::test]
+    async fn test_isolated_providers() {
+        fn isolated_layer(path: &'static str) -> (Router, HttpMetricsLayer) {
+            let registry = Registry::new();
+            let exporter = opentelemetry_prometheus::exporter()
+                .with_registry(registry.clone())
+                .build()
+                .unwrap();
+            let provider = SdkMeterProvider::builder().with_reader(exporter).build();
+            let metrics = HttpMetricsLayerBuilder::new()
+                .with_provider(provider)
+                .with_prometheus_registry(registry)
+                .build();
+            let app = Router::new()
+                .merge(metrics.routes())
+                .route(path, get(handler))
+                .layer(metrics.clone());
+            (app, metrics)
+        }
+
+        let (public, _public_metrics) = isolated_layer("/public");
+        let (admin, _admin_metrics) = isolated_layer("/admin");
+
+        send(&public, Request::builder().uri("/public").body(Body::empty()).unwrap()).await;
+        send(&admin, Request::builder().uri("/admin").body(Body::empty()).unwrap()).await;
+        send(&admin, Request::builder().uri("/admin").body(Body::empty()).unwrap()).await;
+
+        let scrape = |app: Router| async move {
+            let res = app
+                .oneshot(Request::builder().uri("/metrics").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        };
+        let public_text = scrape(public).await;
+        let admin_text = scrape(admin).await;
+
+        assert!(public_text.contains(r#"http_route="/public""#), "{public_text}");
+        assert!(!public_text.contains(r#"http_route="/admin""#), "{public_text}");
+        assert!(admin_text.contains(r#"http_route="/admin""#), "{admin_text}");
+        assert!(!admin_text.contains(r#"http_route="/public""#), "{admin_text}");
+        assert!(
+            admin_text.contains("http_server_request_duration_seconds_count"),
+            "{admin_text}"
+        );
+
+        // nothing leaked into the prometheus default registry
+        let default_text = TextEncoder::new()
+            .encode_to_string(&prometheus::default_registry().gather())
+            .unwrap();
+        assert!(!default_text.contains(r#"http_route="/public""#), "{default_text}");
+        assert!(!default_text.contains(r#"http_route="/admin""#), "{default_text}");
     }
-}
 
-impl<S, R, ResBody> Service<Request<R>> for HttpMetrics<S>
-where
-    S: Service<Request<R>, Response = Response<ResBody>>,
-    ResBody: httpBody,
-{
-    type Response = S::Response;
-    type Error = S::Error;
-    type Future = ResponseFuture<S::Future>;
+    #[tokio::test]
+    async fn test_classifier() {
+        use tower_http::classify::{GrpcErrorsAsFailures, SharedClassifier, StatusInRangeAsFailures};
 
-    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.service.poll_ready(cx)
-    }
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .route("/missing", get(|| async { axum::http::StatusCode::NOT_FOUND }))
+            .layer(
+                metrics.layer(
+                    HttpMetricsLayerBuilder::new()
+                        .with_classifier(StatusInRangeAsFailures::new(400..=599).into_make_classifier())
+                        .with_error_counter(crate::ErrorKind::ServerOnly),
+                ),
+            );
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        send(&app, Request::get("/missing").body(Body::empty()).unwrap()).await;
 
-    fn call(&mut self, req: Request<R>) -> Self::Future {
-        let url_scheme = if self.state.is_tls {
-            "https".to_string()
-        } else {
-            (|| {
-                if let Some(scheme) = req.headers().get("X-Forwarded-Proto") {
-                    return scheme.to_str().unwrap().to_string();
-                } else if let Some(scheme) = req.headers().get("X-Forwarded-Protocol") {
-                    return scheme.to_str().unwrap().to_string();
-                }
-                if req.headers().get("X-Forwarded-Ssl").is_some().to_string() == "on" {
-                    return "https".to_string();
-                }
-                if let Some(scheme) = req.headers().get("X-Url-Scheme") {
-                    scheme.to_str().unwrap().to_string()
-                } else {
-                    "http".to_string()
-                }
-            })()
+        let output = metrics.gather();
+        let error_type = r#"error_type="Status code: 404 Not Found""#;
+        let line = |prefix: &str, route: &str| {
+            output
+                .lines()
+                .find(|l| l.starts_with(prefix) && l.contains(&format!(r#"http_route="{route}""#)))
+                .unwrap_or_else(|| panic!("{prefix} {route}: {output}"))
+                .to_string()
         };
-        // ref https://github.com/open-telemetry/semantic-conventions/blob/main/docs/http/http-metrics.md#metric-httpserveractive_requests
-        // http.request.method and url.scheme is required
-        self.state.metric.req_active.add(
-            1,
-            &[
-                KeyValue::new("http.request.method", req.method().as_str().to_string()),
-                KeyValue::new("url.scheme", url_scheme.clone()),
-            ],
+        assert!(line("http_server_request_duration_seconds_count", "/missing").contains(error_type));
+        assert!(!line("http_server_request_duration_seconds_count", "/").contains("error_type"));
+        // the classifier decides the errors, not the ErrorKind
+        assert!(line("http_server_request_errors_total", "/missing").contains(error_type));
+        assert!(
+            !output.contains(r#"http_server_request_errors_total{http_request_method="GET",http_response_status_code="200""#)
         );
-        let start = Instant::now();
-        let method = req.method().clone().to_string();
-        let path = if let Some(matched_path) = req.extensions().get::<MatchedPath>() {
-            matched_path.as_str().to_owned()
-        } else {
-            "".to_owned()
-        };
-
-        let host = req
-            .headers()
-            .get(http::header::HOST)
-            .and_then(|h| h.to_str().ok())
-            .unwrap_or("unknown")
-            .to_string();
 
-        let req_size = compute_approximate_request_size(&req);
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route(
+                "/trailers",
+                get(|| async {
+                    let mut trailers = axum::http::HeaderMap::new();
+                    trailers.insert("grpc-status", "5".parse().unwrap());
+                    let body = GrpcBody {
+                        data: Some(bytes::Bytes::from_static(b"hello")),
+                        trailers: Some(trailers),
+                    };
+                    ([("content-type", "application/grpc")], Body::new(body))
+                }),
+            )
+            .route(
+                "/headers",
+                get(|| async { [("content-type", "application/grpc"), ("grpc-status", "13")] }),
+            )
+            .route(
+                "/ok",
+                get(|| async {
+                    let mut trailers = axum::http::HeaderMap::new();
+                    trailers.insert("grpc-status", "0".parse().unwrap());
+                    let body = GrpcBody {
+                        data: None,
+                        trailers: Some(trailers),
+                    };
+                    ([("content-type", "application/grpc")], Body::new(body))
+                }),
+            )
+            .layer(
+                metrics
+                    .layer(HttpMetricsLayerBuilder::new().with_classifier(SharedClassifier::new(GrpcErrorsAsFailures::new()))),
+            );
 
-        // for scheme, see github.com/labstack/echo/v4@v4.11.1/context.go
-        // we can not use req.uri().scheme() since for non-absolute uri, it is always None
+        let res = send(&app, Request::get("/trailers").body(Body::empty()).unwrap()).await;
+        // the classification waits for the trailers
+        assert!(!metrics.gather().contains(r#"http_route="/trailers""#));
+        axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        send(&app, Request::get("/headers").body(Body::empty()).unwrap()).await;
+        let res = send(&app, Request::get("/ok").body(Body::empty()).unwrap()).await;
+        axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
 
-        ResponseFuture {
-            inner: self.service.call(req),
-            start,
-            method,
-            path,
-            host,
-            req_size: req_size as u64,
-            state: self.state.clone(),
-            url_scheme,
-        }
+        let output = metrics.gather();
+        assert!(output.contains(r#"http_server_request_duration_seconds_count{error_type="Code: 5",http_request_method="GET",http_response_status_code="200",http_route="/trailers""#), "{output}");
+        assert!(output.contains(r#"http_server_request_duration_seconds_count{error_type="Code: 13",http_request_method="GET",http_response_status_code="200",http_route="/headers""#), "{output}");
+        assert!(output.contains(r#"http_server_request_duration_seconds_count{http_request_method="GET",http_response_status_code="200",http_route="/ok""#), "{output}");
     }
-}
 
-/// compute approximate request size
-///
-/// the implementation refs [labstack/echo-contrib 's prometheus middleware](https://github.com/labstack/echo-contrib/blob/db8911a1af7abb6bdafbd999adada548fd9c0849/echoprometheus/prometheus.go#L329)
-fn compute_approximate_request_size<T>(req: &Request<T>) -> usize {
-    let mut s = 0;
-    s += req.uri().path().len();
-    s += req.method().as_str().len();
+    #[tokio::test]
+    async fn test_error_type_attribute() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .route("/fail", get(|| async { axum::http::StatusCode::INTERNAL_SERVER_ERROR }))
+            .route("/missing", get(|| async { axum::http::StatusCode::NOT_FOUND }))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_error_type_attribute(true)));
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        send(&app, Request::get("/fail").body(Body::empty()).unwrap()).await;
+        send(&app, Request::get("/missing").body(Body::empty()).unwrap()).await;
 
-    req.headers().iter().for_each(|(k, v)| {
-        s += k.as_str().len();
-        s += v.as_bytes().len();
-    });
+        let output = metrics.gather();
+        assert!(output.contains(r#"http_server_request_duration_seconds_count{error_type="500",http_request_method="GET",http_response_status_code="500",http_route="/fail""#), "{output}");
+        assert!(output.contains(r#"http_server_request_duration_seconds_count{error_type="404",http_request_method="GET",http_response_status_code="404",http_route="/missing""#), "{output}");
+        assert!(output.contains(r#"http_server_request_duration_seconds_count{http_request_method="GET",http_response_status_code="200",http_route="/""#), "{output}");
 
-    s += req.uri().host().map(|h| h.len()).unwrap_or(0);
+        let layer = metrics.layer(HttpMetricsLayerBuilder::new().with_error_type_attribute(true));
+        let service = layer.layer(tower::service_fn(|_req: Request<crate::RequestBody<Body>>| async {
+            Err::<axum::response::Response, _>(std::io::Error::other("boom"))
+        }));
+        let res = service.oneshot(Request::get("/").body(Body::empty()).unwrap()).await;
+        assert!(res.is_err());
 
-    s += req
-        .headers()
-        .get(http::header::CONTENT_LENGTH)
-        .map(|v| v.to_str().unwrap().parse::<usize>().unwrap_or(0))
-        .unwrap_or(0);
-    s
-}
+        let output = metrics.gather();
+        assert!(
+            output
+                .contains(r#"http_server_request_failures_total{error_type="std::io::error::Error",http_request_method="GET""#),
+            "{output}"
+        );
 
-impl<F, B: httpBody, E> Future for ResponseFuture<F>
-where
-    F: Future<Output = Result<Response<B>, E>>,
-{
-    type Output = Result<Response<B>, E>;
+        // opt-in, the existing series are unchanged by default
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/fail", get(|| async { axum::http::StatusCode::INTERNAL_SERVER_ERROR }))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new()));
+        send(&app, Request::get("/fail").body(Body::empty()).unwrap()).await;
+        assert!(!metrics.gather().contains("error_type"));
+    }
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let this = self.project();
-        let response = ready!(this.inner.poll(cx))?;
+    #[tokio::test]
+    async fn test_detailed_attributes_on_error_only() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .route("/fail", get(|| async { axum::http::StatusCode::INTERNAL_SERVER_ERROR }))
+            .layer(
+                metrics.layer(
+                    HttpMetricsLayerBuilder::new()
+                        .with_detailed_attributes_on_error_only(true)
+                        .with_constant_labels(vec![KeyValue::new("env", "dev")]),
+                ),
+            );
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        send(&app, Request::get("/fail").body(Body::empty()).unwrap()).await;
 
-        this.state.metric.req_active.add(
-            -1,
-            &[
-                KeyValue::new("http.request.method", this.method.clone()),
-                KeyValue::new("url.scheme", this.url_scheme.clone()),
-            ],
-        );
+        let output = metrics.gather();
+        assert!(output.contains(r#"http_server_request_duration_seconds_count{env="dev",http_request_method="GET",http_response_status_class="2xx",otel_scope_name="#), "{output}");
+        assert!(output.contains(r#"http_server_request_duration_seconds_count{env="dev",http_request_method="GET",http_response_status_code="500",http_route="/fail",server_address="unknown""#), "{output}");
+        assert!(!output.contains(r#"http_route="/",""#), "{output}");
+    }
 
-        if (this.state.skipper.skip)(this.path.as_str()) {
-            return Poll::Ready(Ok(response));
-        }
+    #[tokio::test]
+    async fn test_phase_breakdown() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route(
+                "/stream",
+                get(|| async {
+                    let chunks = [Ok::<_, std::convert::Infallible>("hello"), Ok(" "), Ok("world")];
+                    Body::from_stream(futures_util::stream::iter(chunks))
+                }),
+            )
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_phase_breakdown(true)));
+
+        let res = send(&app, Request::get("/stream").body(Body::empty()).unwrap()).await;
+        let output = metrics.gather();
+        assert!(output.contains(r#"http_server_handler_duration_seconds_count{http_request_method="GET",http_response_status_code="200",http_route="/stream""#), "{output}");
+        // the body duration is recorded once the body is finished
+        assert!(
+            !output.contains("http_server_response_body_duration_seconds_count"),
+            "{output}"
+        );
 
-        let latency = this.start.elapsed().as_secs_f64();
-        let status = response.status().as_u16().to_string();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"hello world");
+        let output = metrics.gather();
+        assert!(output.contains(r#"http_server_response_body_duration_seconds_count{http_request_method="GET",http_response_status_code="200",http_route="/stream""#), "{output}");
 
-        let res_size = response.body().size_hint().upper().unwrap_or(0);
+        // disabled by default
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new()));
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        assert!(!metrics.gather().contains("http_server_handler_duration"));
+    }
 
-        let labels = [
-            KeyValue::new("http.request.method", this.method.clone()),
-            KeyValue::new("http.route", this.path.clone()),
-            KeyValue::new("http.response.status_code", status),
-            // server.address: Name of the local HTTP server that received the request.
-            // Determined by using the first of the following that applies
-            //
-            // 1. The primary server name of the matched virtual host. MUST only include host identifier.
-            // 2. Host identifier of the request target if it's sent in absolute-form.
-            // 3. Host identifier of the Host header
-            KeyValue::new("server.address", this.host.clone()),
-        ];
-        this.state.metric.req_size.record(*this.req_size, &labels);
+    #[tokio::test]
+    async fn test_request_response_size_buckets() {
+        let metrics = TestMetrics::new();
+        let app = Router::new().route("/", get(handler)).layer(
+            metrics.layer(
+                HttpMetricsLayerBuilder::new()
+                    .with_size_buckets(vec![10.0, 20.0])
+                    .with_request_size_buckets(vec![64.0, 128.0])
+                    .with_response_size_buckets(vec![1048576.0, 16777216.0]),
+            ),
+        );
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
 
-        this.state.metric.res_size.record(res_size, &labels);
+        let output = metrics.gather();
+        let buckets = |prefix: &str| {
+            output
+                .lines()
+                .filter(|l| l.starts_with(prefix))
+                .filter_map(|l| l.split(r#"le=""#).nth(1)?.split('"').next())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            buckets("http_server_request_size_bytes_bucket"),
+            ["64", "128", "+Inf"],
+            "{output}"
+        );
+        assert_eq!(
+            buckets("http_server_response_size_bytes_bucket"),
+            ["1048576", "16777216", "+Inf"],
+            "{output}"
+        );
 
-        this.state.metric.req_duration.record(latency, &labels);
+        // the shorthand sets both
+        let metrics = TestMetrics::new();
+        let app = Router::new().route("/", get(handler)).layer(
+            metrics.layer(
+                HttpMetricsLayerBuilder::new()
+                    .with_size_buckets(vec![10.0, 20.0])
+                    .with_response_size_buckets(vec![1048576.0]),
+            ),
+        );
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
 
-        Ready(Ok(response))
+        let output = metrics.gather();
+        assert!(output.contains(r#"http_server_request_size_bytes_bucket{http_request_method="GET",http_response_status_code="200",http_route="/",server_address="unknown",otel_scope_name="test",le="20"}"#), "{output}");
+        assert!(output.contains(r#"http_server_response_size_bytes_bucket{http_request_method="GET",http_response_status_code="200",http_route="/",server_address="unknown",otel_scope_name="test",le="1048576"}"#), "{output}");
+        assert!(!output.contains(r#"http_server_response_size_bytes_bucket{http_request_method="GET",http_response_status_code="200",http_route="/",server_address="unknown",otel_scope_name="test",le="20"}"#), "{output}");
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use crate::HttpMetricsLayer;
-    use crate::HttpMetricsLayerBuilder;
-    use axum::extract::State;
-    use axum::routing::get;
-    use axum::Router;
-    use opentelemetry::{global, Context, KeyValue};
-    use opentelemetry_sdk::metrics::SdkMeterProvider;
-    use prometheus::{Encoder, Registry, TextEncoder};
-    use std::sync::Arc;
+    #[tokio::test]
+    async fn test_scheme_attribute() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_scheme_attribute(false)));
+        let req = Request::get("/")
+            .header("X-Forwarded-Proto", "https")
+            .body(Body::empty())
+            .unwrap();
+        send(&app, req).await;
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(!output.contains("url_scheme"), "{output}");
+        let active = output
+            .lines()
+            .filter(|l| l.starts_with("http_server_active_requests{"))
+            .collect::<Vec<_>>();
+        assert_eq!(active.len(), 1, "{output}");
+        assert!(
+            active[0].starts_with(r#"http_server_active_requests{http_request_method="GET",otel_scope_name="#),
+            "{output}"
+        );
+        assert!(active[0].ends_with(" 0"), "{output}");
+    }
 
     #[tokio::test]
-    async fn test_prometheus_exporter() {
-        let _cx = Context::current();
+    async fn test_with_meter() {
+        let metrics = TestMetrics::new();
+        let other = TestMetrics::new();
+        let layer = HttpMetricsLayerBuilder::new()
+            .with_meter(metrics.provider.meter("shared"))
+            .with_provider(other.provider.clone())
+            .build();
+        let app = Router::new().route("/", get(handler)).layer(layer);
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
 
-        let registry = Registry::new();
+        let output = metrics.gather();
+        assert!(
+            output.contains(r#"http_route="/",server_address="unknown",otel_scope_name="shared""#),
+            "{output}"
+        );
+        // the meter takes precedence over the provider
+        assert!(!other.gather().contains("http_server_request_duration"));
+    }
 
-        // init prometheus exporter
-        let exporter = opentelemetry_prometheus::exporter()
-            .with_registry(registry.clone())
-            .build()
+    #[tokio::test]
+    async fn test_separate_unmatched_instrument() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_separate_unmatched_instrument(true)));
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        send(&app, Request::get("/wp-login.php").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"http_server_unmatched_request_duration_seconds_count{http_request_method="GET",http_response_status_code="404",otel_scope_name="test"} 1"#), "{output}");
+        assert!(output.contains(r#"http_server_unmatched_response_size_bytes_count{http_request_method="GET",http_response_status_code="404",otel_scope_name="test"} 1"#), "{output}");
+        assert!(output.contains(r#"http_server_unmatched_request_size_bytes_count{http_request_method="GET",http_response_status_code="404",otel_scope_name="test"} 1"#), "{output}");
+        assert!(output.contains(r#"http_server_request_duration_seconds_count{http_request_method="GET",http_response_status_code="200",http_route="/""#), "{output}");
+        assert!(!output.contains("__unmatched__"), "{output}");
+        let active = output
+            .lines()
+            .find(|l| l.starts_with("http_server_active_requests{"))
             .unwrap();
+        assert!(active.ends_with(" 0"), "{output}");
 
-        let provider = SdkMeterProvider::builder().with_reader(exporter).build();
+        // the unmatched sizes follow the exact sizes of the matched requests
+        let metrics = TestMetrics::new();
+        let app = Router::new().route("/", get(handler)).layer(
+            metrics.layer(
+                HttpMetricsLayerBuilder::new()
+                    .with_separate_unmatched_instrument(true)
+                    .with_exact_request_size(true)
+                    .with_exact_response_size(true),
+            ),
+        );
+        send(&app, Request::post("/upload").body(Body::from("hello")).unwrap()).await;
 
-        // init the global meter provider
-        global::set_meter_provider(provider.clone());
+        let output = metrics.gather();
+        assert!(output.contains(r#"http_server_unmatched_request_size_bytes_count{http_request_method="POST",http_response_status_code="404",otel_scope_name="test"} 1"#), "{output}");
+        assert!(output.contains(r#"http_server_unmatched_response_size_bytes_count{http_request_method="POST",http_response_status_code="404",otel_scope_name="test"} 1"#), "{output}");
 
-        let meter = global::meter("my-app");
+        // and so does with_size_metrics_on_success_only
+        let metrics = TestMetrics::new();
+        let app = Router::new().route("/", get(handler)).layer(
+            metrics.layer(
+                HttpMetricsLayerBuilder::new()
+                    .with_separate_unmatched_instrument(true)
+                    .with_size_metrics_on_success_only(true),
+            ),
+        );
+        send(&app, Request::get("/wp-login.php").body(Body::empty()).unwrap()).await;
 
-        // Use two instruments
-        let counter = meter.u64_counter("a.counter").with_description("Counts things").build();
-        let recorder = meter.u64_histogram("a.histogram").with_description("Records values").build();
+        let output = metrics.gather();
+        assert!(
+            output.contains("http_server_unmatched_request_duration_seconds_count"),
+            "{output}"
+        );
+        assert!(!output.contains("http_server_unmatched_request_size"), "{output}");
+        assert!(!output.contains("http_server_unmatched_response_size"), "{output}");
+    }
 
-        counter.add(100, &[KeyValue::new("key", "value")]);
-        recorder.record(100, &[KeyValue::new("key", "value")]);
+    #[tokio::test]
+    async fn test_original_uri() {
+        let metrics = TestMetrics::new();
+        let sub = Router::new().route("/sub1", get(handler)).layer(
+            metrics.layer(
+                HttpMetricsLayerBuilder::new()
+                    .with_route_attribute_mode(crate::RouteMode::Both)
+                    .with_original_route_attribute(true),
+            ),
+        );
+        let app = Router::new().nest("/sub", sub);
+        send(&app, Request::get("/sub/sub1?page=2").body(Body::empty()).unwrap()).await;
 
-        // Encode data as text or protobuf
-        let encoder = TextEncoder::new();
-        let metric_families = registry.gather();
-        let mut result = Vec::new();
-        encoder.encode(&metric_families, &mut result).expect("encode failed");
-        println!("{}", String::from_utf8(result).unwrap());
+        let output = metrics.gather();
+        assert!(output.contains(r#"http_server_request_duration_seconds_count{http_request_method="GET",http_response_status_code="200",http_route="/sub/sub1",http_route_original="/sub/sub1",http_target="/sub/sub1",server_address="unknown""#), "{output}");
+        assert!(!output.contains(r#"="/sub1""#), "{output}");
     }
 
     #[tokio::test]
-    async fn test_prom_exporter_builder() {
-        let metrics = HttpMetricsLayerBuilder::new().build();
-        let _app = Router::<HttpMetricsLayer>::new()
-            // export metrics at `/metrics` endpoint
-            .route(
-                "/metrics",
-                get(|| async {
-                    let mut buffer = Vec::new();
-                    let encoder = TextEncoder::new();
-                    encoder.encode(&prometheus::gather(), &mut buffer).unwrap();
-                    // return metrics
-                    String::from_utf8(buffer).unwrap()
-                }),
-            )
+    async fn test_empty_server_address_fallback() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
             .route("/", get(handler))
-            .route("/hello", get(handler))
-            .route("/world", get(handler))
-            // add the metrics middleware
-            .layer(metrics);
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_server_address_fallback(String::new())));
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        let req = Request::get("/").header("Host", "example.com").body(Body::empty()).unwrap();
+        send(&app, req).await;
 
-        async fn handler() -> &'static str {
-            "<h1>Hello, World!</h1>"
+        let output = metrics.gather();
+        assert!(output.contains(r#"http_server_request_duration_seconds_count{http_request_method="GET",http_response_status_code="200",http_route="/",otel_scope_name="test"} 1"#), "{output}");
+        assert!(output.contains(r#"http_server_request_duration_seconds_count{http_request_method="GET",http_response_status_code="200",http_route="/",server_address="example.com",otel_scope_name="test"} 1"#), "{output}");
+        assert!(!output.contains(r#"server_address="""#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_active_requests_attributes() {
+        use crate::ActiveRequestsAttributes;
+
+        for (attributes, expected) in [
+            (
+                ActiveRequestsAttributes::MethodScheme,
+                r#"http_server_active_requests{http_request_method="GET",url_scheme="http",otel_scope_name="#,
+            ),
+            (
+                ActiveRequestsAttributes::MethodOnly,
+                r#"http_server_active_requests{http_request_method="GET",otel_scope_name="#,
+            ),
+            (
+                ActiveRequestsAttributes::None,
+                r#"http_server_active_requests{otel_scope_name="#,
+            ),
+        ] {
+            let metrics = TestMetrics::new();
+            let layer = metrics.layer(HttpMetricsLayerBuilder::new().with_active_requests_attributes(attributes));
+            let app = Router::new().route("/", get(handler)).layer(layer.clone());
+            let mut svc = layer.layer(app.clone());
+            let pending = svc.call(Request::get("/").body(Body::empty()).unwrap());
+
+            let output = metrics.gather();
+            let active = output
+                .lines()
+                .filter(|l| l.starts_with("http_server_active_requests{"))
+                .collect::<Vec<_>>();
+            assert_eq!(active.len(), 1, "{output}");
+            assert!(active[0].starts_with(expected), "{attributes:?}: {output}");
+            assert!(active[0].ends_with(" 1"), "{output}");
+
+            drop(pending);
+            send(&app, Request::post("/").body(Body::empty()).unwrap()).await;
+            let output = metrics.gather();
+            assert!(
+                output
+                    .lines()
+                    .filter(|l| l.starts_with("http_server_active_requests{"))
+                    .all(|l| l.ends_with(" 0")),
+                "{output}"
+            );
         }
     }
 
     #[tokio::test]
-    async fn test_builder_with_state_router() {
-        #[derive(Clone)]
-        struct AppState {}
+    async fn test_max_recorded_duration() {
+        use crate::testing::{PointValue, TestRecorder};
 
-        let metrics = HttpMetricsLayerBuilder::new().build();
-        let _app: Router<AppState> = Router::new()
+        let recorder = TestRecorder::new();
+        let app = Router::new()
             .route(
-                "/metrics",
+                "/slow",
                 get(|| async {
-                    let mut buffer = Vec::new();
-                    let encoder = TextEncoder::new();
-                    encoder.encode(&prometheus::gather(), &mut buffer).unwrap();
-                    // return metrics
-                    String::from_utf8(buffer).unwrap()
+                    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                    "slow"
                 }),
             )
             .route("/", get(handler))
-            .route("/hello", get(handler))
-            .route("/world", get(handler))
-            // add the metrics middleware
-            .layer(metrics)
-            .with_state(AppState {});
+            .layer(
+                recorder.layer(HttpMetricsLayerBuilder::new().with_max_recorded_duration(std::time::Duration::from_millis(10))),
+            );
+        send(&app, Request::get("/slow").body(Body::empty()).unwrap()).await;
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
 
-        async fn handler(_state: State<AppState>) -> &'static str {
-            "<h1>Hello, World!</h1>"
-        }
+        let duration = recorder.points("http.server.request.duration");
+        let slow = duration
+            .iter()
+            .find(|p| p.attribute("http.route") == Some(&"/slow".into()))
+            .unwrap();
+        assert!(
+            matches!(slow.value, PointValue::Histogram { count: 1, sum, .. } if sum == 0.01),
+            "{slow:?}"
+        );
+
+        let clamped = recorder.points("http.server.duration.clamped");
+        assert_eq!(clamped.len(), 1, "{clamped:?}");
+        assert_eq!(clamped[0].attribute("http.route"), Some(&"/slow".into()));
+        assert_eq!(clamped[0].value, PointValue::Sum(1.0));
     }
 
     #[tokio::test]
-    async fn test_builder_with_arced_skipper() {
+    async fn test_size_metrics_on_success_only() {
+        use crate::testing::TestRecorder;
+
+        let recorder = TestRecorder::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .route("/fail", get(|| async { axum::http::StatusCode::INTERNAL_SERVER_ERROR }))
+            .layer(recorder.layer(HttpMetricsLayerBuilder::new().with_size_metrics_on_success_only(true)));
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+        send(&app, Request::get("/fail").body(Body::empty()).unwrap()).await;
+
+        let routes = |instrument: &str| {
+            recorder
+                .points(instrument)
+                .iter()
+                .filter_map(|p| p.attribute("http.route").map(|v| v.to_string()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(routes("http.server.request.size"), ["/"]);
+        assert_eq!(routes("http.server.response.size"), ["/"]);
+        let mut durations = routes("http.server.request.duration");
+        durations.sort();
+        assert_eq!(durations, ["/", "/fail"]);
+    }
+
+    #[tokio::test]
+    async fn test_route_attributes() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/checkout/{id}", get(handler))
+            .route("/", get(handler))
+            .layer(metrics.layer(
+                HttpMetricsLayerBuilder::new().with_route_attributes(std::collections::HashMap::from([(
+                    "/checkout/{id}".to_string(),
+                    vec![KeyValue::new("domain", "payments"), KeyValue::new("team", "checkout")],
+                )])),
+            ));
+        send(&app, Request::get("/checkout/42").body(Body::empty()).unwrap()).await;
+        send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"http_server_request_duration_seconds_count{domain="payments",http_request_method="GET",http_response_status_code="200",http_route="/checkout/{id}",server_address="unknown",team="checkout""#), "{output}");
+        assert!(output.contains(r#"http_server_request_duration_seconds_count{http_request_method="GET",http_response_status_code="200",http_route="/",server_address="unknown",otel_scope_name="#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_poll_ready_metric() {
+        use crate::testing::{PointValue, TestRecorder};
+
+        /// pending on the first `poll_ready`, ready afterwards
         #[derive(Clone)]
-        struct AppState {}
+        struct NotReadyOnce(bool);
 
-        let metrics = HttpMetricsLayerBuilder::new()
-            .with_skipper(crate::PathSkipper::new_with_fn(Arc::new(|_: &str| true)))
-            .build();
-        let _app: Router<AppState> = Router::new()
-            .route(
-                "/metrics",
-                get(|| async {
-                    let mut buffer = Vec::new();
-                    let encoder = TextEncoder::new();
-                    encoder.encode(&prometheus::gather(), &mut buffer).unwrap();
-                    // return metrics
-                    String::from_utf8(buffer).unwrap()
-                }),
-            )
+        impl Service<Request<crate::RequestBody<Body>>> for NotReadyOnce {
+            type Response = http::Response<Body>;
+            type Error = std::convert::Infallible;
+            type Future = std::future::Ready<Result<Self::Response, Self::Error>>;
+
+            fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+                if self.0 {
+                    return std::task::Poll::Ready(Ok(()));
+                }
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                std::task::Poll::Pending
+            }
+
+            fn call(&mut self, _req: Request<crate::RequestBody<Body>>) -> Self::Future {
+                std::future::ready(Ok(http::Response::new(Body::empty())))
+            }
+        }
+
+        let recorder = TestRecorder::new();
+        let layer = recorder.layer(HttpMetricsLayerBuilder::new().with_poll_ready_metric(true));
+        let mut svc = layer.layer(NotReadyOnce(false));
+        ServiceExt::<Request<Body>>::ready(&mut svc).await.unwrap();
+        svc.call(Request::get("/").body(Body::empty()).unwrap()).await.unwrap();
+        // already ready, no wait is recorded
+        ServiceExt::<Request<Body>>::ready(&mut svc).await.unwrap();
+
+        let points = recorder.points("http.server.poll_ready.duration");
+        assert_eq!(points.len(), 1, "{points:?}");
+        assert!(
+            matches!(points[0].value, PointValue::Histogram { count: 1, .. }),
+            "{points:?}"
+        );
+
+        // disabled by default
+        let recorder = TestRecorder::new();
+        let mut svc = recorder.layer(HttpMetricsLayerBuilder::new()).layer(NotReadyOnce(false));
+        ServiceExt::<Request<Body>>::ready(&mut svc).await.unwrap();
+        assert!(recorder.points("http.server.poll_ready.duration").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_path_skipper_replace_fn() {
+        use crate::testing::{PointValue, TestRecorder};
+
+        let recorder = TestRecorder::new();
+        let skipper = crate::PathSkipper::new(|_| false);
+        let app = Router::new()
+            .route("/noisy", get(handler))
+            .layer(recorder.layer(HttpMetricsLayerBuilder::new().with_skipper(skipper.clone())));
+        send(&app, Request::get("/noisy").body(Body::empty()).unwrap()).await;
+
+        // the layer holds a clone, it observes the new callable
+        skipper.replace_fn(Arc::new(|path: &str| path == "/noisy"));
+        send(&app, Request::get("/noisy").body(Body::empty()).unwrap()).await;
+
+        let duration = recorder.points("http.server.request.duration");
+        assert_eq!(duration.len(), 1, "{duration:?}");
+        assert!(
+            matches!(duration[0].value, PointValue::Histogram { count: 1, .. }),
+            "{duration:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tls_connection_extension() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
             .route("/", get(handler))
-            // add the metrics middleware
-            .layer(metrics)
-            .with_state(AppState {});
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new()));
+        // the extension wins over the forwarded headers
+        let req = Request::get("/")
+            .header("X-Forwarded-Proto", "http")
+            .extension(crate::TlsConnection(true))
+            .body(Body::empty())
+            .unwrap();
+        send(&app, req).await;
 
-        async fn handler(_state: State<AppState>) -> &'static str {
-            "<h1>Hello, World!</h1>"
+        let output = metrics.gather();
+        assert!(output.contains(r#"url_scheme="https""#), "{output}");
+        assert!(!output.contains(r#"url_scheme="http""#), "{output}");
+
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_is_tls(true)));
+        let req = Request::get("/")
+            .extension(crate::TlsConnection(false))
+            .body(Body::empty())
+            .unwrap();
+        send(&app, req).await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"url_scheme="http""#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_path_skipper_none() {
+        let metrics = TestMetrics::new();
+        let app = Router::new()
+            .route("/metrics", get(handler))
+            .layer(metrics.layer(HttpMetricsLayerBuilder::new().with_skipper(crate::PathSkipper::none())));
+        send(&app, Request::get("/metrics").body(Body::empty()).unwrap()).await;
+
+        let output = metrics.gather();
+        assert!(output.contains(r#"http_route="/metrics""#), "{output}");
+    }
+
+    #[tokio::test]
+    async fn test_baggage_attributes() {
+        use crate::testing::TestRecorder;
+        use opentelemetry::baggage::BaggageExt;
+
+        let recorder = TestRecorder::new();
+        let app = Router::new().route("/", get(handler)).layer(recorder.layer(
+            HttpMetricsLayerBuilder::new().with_baggage_attributes(vec!["tenant.tier".to_string(), "missing".to_string()]),
+        ));
+        {
+            let _guard = opentelemetry::Context::current_with_baggage(vec![
+                KeyValue::new("tenant.tier", "gold"),
+                KeyValue::new("user.id", "42"),
+            ])
+            .attach();
+            send(&app, Request::get("/").body(Body::empty()).unwrap()).await;
         }
+
+        let duration = recorder.points("http.server.request.duration");
+        assert_eq!(duration.len(), 1, "{duration:?}");
+        assert_eq!(duration[0].attribute("tenant.tier"), Some(&"gold".into()));
+        assert_eq!(duration[0].attribute("missing"), None);
+        assert_eq!(duration[0].attribute("user.id"), None);
+    }
+
+    #[tokio::test]
+    async fn test_generic_request_body() {
+        use crate::testing::TestRecorder;
+
+        let recorder = TestRecorder::new();
+        let layer = recorder.layer(HttpMetricsLayerBuilder::new());
+        // the inner service gets the request body type of the caller, not an `axum::body::Body`
+        let service = layer.layer(tower::service_fn(|req: Request<crate::RequestBody<String>>| async move {
+            let body = axum::body::to_bytes(Body::new(req.into_body()), usize::MAX).await.unwrap();
+            Ok::<_, std::convert::Infallible>(http::Response::new(Body::from(body)))
+        }));
+        let res = service
+            .oneshot(Request::get("/").body("hello".to_string()).unwrap())
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(Body::new(res.into_body()), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"hello");
+
+        assert_eq!(recorder.points("http.server.request.duration").len(), 1);
     }
 }